@@ -594,8 +594,12 @@ class MainApplication {
     // Note: Hotkey registration is now handled by the HotkeyEngine
     // and initiated after the window loads.
 
-    this.systemAgentService.on('keypress', (event: SystemAgentEvent) => this.handleKeyEvent(event, 'press'));
-    this.systemAgentService.on('keyrelease', (event: SystemAgentEvent) => this.handleKeyEvent(event, 'release'));
+    // The raw key stream is opt-in; we need it for the on-screen key display.
+    this.systemAgentService.subscribe(['raw_keys']).catch(err => {
+      console.error('[Main] Failed to subscribe to raw key events:', err);
+    });
+    this.systemAgentService.on('key_press', (event: SystemAgentEvent) => this.handleKeyEvent(event, 'press'));
+    this.systemAgentService.on('key_release', (event: SystemAgentEvent) => this.handleKeyEvent(event, 'release'));
     this.systemAgentService.on('error', (error: any) => {
         console.error('System Agent Service Error:', error);
     });
@@ -6,6 +6,15 @@ export interface HotkeyEngineOptions {
   systemAgentService: SystemAgentService;
 }
 
+/**
+ * The id a binding is registered under with the system agent. An action may
+ * have several shortcuts and a shortcut several actions, so neither is unique
+ * on its own.
+ */
+function bindingId(binding: HotkeyBinding): string {
+  return `${binding.actionId}@${binding.shortcut}`;
+}
+
 export class HotkeyEngine extends EventEmitter {
   private options: HotkeyEngineOptions;
   private bindings: Map<string, HotkeyBinding> = new Map();
@@ -33,24 +42,49 @@ export class HotkeyEngine extends EventEmitter {
   }
 
   public registerBindings(bindings: HotkeyBinding[]): void {
-    console.log(`[HotkeyEngine] Registering ${bindings.length} hotkey bindings using batch operation`);
-    
-    // Step 1: Unregister all existing hotkeys with a single command
-    this.options.systemAgentService.unregisterAllHotkeys();
-    
-    // Step 2: Register new hotkeys in batch, but only for actions that have handlers
+    const agent = this.options.systemAgentService;
+
+    // Only register hotkeys for actions that have handlers. Bindings share
+    // their shortcut so that one key combination can trigger several actions.
     const hotkeysToRegister = bindings
       .filter(binding => this.actions.has(binding.actionId))
       .map(binding => ({
-        id: binding.shortcut,  // Use shortcut as the unique ID
-        shortcut: binding.shortcut
+        id: bindingId(binding),
+        shortcut: binding.shortcut,
+        on_conflict: 'share' as const
       }));
+
+    if (agent.supportsCommand('sync_hotkeys')) {
+      // Let the agent diff against what it holds, so unchanged hotkeys stay grabbed
+      console.log(`[HotkeyEngine] Syncing ${hotkeysToRegister.length} hotkey bindings`);
+      agent.syncHotkeys(hotkeysToRegister)
+        .then(changes => {
+          for (const change of changes.filter(change => change.action === 'failed')) {
+            console.error(`[HotkeyEngine] Failed to sync hotkey "${change.id}": ${change.error}`);
+          }
+        })
+        .catch(err => {
+          console.error('[HotkeyEngine] Failed to sync hotkeys:', err);
+        });
+      this.bindings = new Map(bindings.map(b => [bindingId(b), b]));
+      return;
+    }
+
+    console.log(`[HotkeyEngine] Registering ${bindings.length} hotkey bindings using batch operation`);
+    
+    // Step 1: Unregister all existing hotkeys with a single command
+    agent.unregisterAllHotkeys().catch(err => {
+      console.error('[HotkeyEngine] Failed to unregister hotkeys:', err);
+    });
     
+    // Step 2: Register new hotkeys in batch
     if (hotkeysToRegister.length > 0) {
-      this.options.systemAgentService.registerHotkeys(hotkeysToRegister);
+      agent.registerHotkeys(hotkeysToRegister).catch(err => {
+        console.error('[HotkeyEngine] Failed to register hotkeys:', err);
+      });
     }
     
-    this.bindings = new Map(bindings.map(b => [b.shortcut, b]));
+    this.bindings = new Map(bindings.map(b => [bindingId(b), b]));
     console.log(`[HotkeyEngine] Successfully registered ${hotkeysToRegister.length} hotkeys`);
   }
 
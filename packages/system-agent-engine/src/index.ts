@@ -2,14 +2,125 @@ import { spawn, ChildProcess } from 'child_process';
 import { EventEmitter } from 'events';
 
 export interface SystemAgentEvent {
-  event: 'hotkey_pressed' | 'key_press' | 'key_release';
+  /** Envelope version, sequence number and monotonic timestamp (absent in legacy output). */
+  v?: number;
+  seq?: number;
+  ts?: number;
+  type?: 'ready' | 'result' | 'hotkey_pressed' | 'hotkey_released' | 'sequence_pending' | 'sequence_cancelled' | 'captured_key' | 'capture_ended' | 'key_press' | 'key_release' | 'error' | 'dropped' | 'shutting_down';
+  /** Legacy discriminators, only present when the agent runs with --legacy-events. */
+  event?: string;
+  event_type?: string;
   // Add other potential properties from the agent's JSON output
   [key: string]: any;
 }
 
+export interface SystemAgentResult {
+  type: 'result';
+  request_id: string;
+  ok: boolean;
+  error?: string;
+  /** Present in replies to query commands such as `list_hotkeys` and `status`. */
+  data?: any;
+}
+
+/** `reject` fails on a taken shortcut; `share` delivers presses to every hotkey that also shares it. */
+export type SystemAgentConflictPolicy = 'reject' | 'share';
+
+export interface SystemAgentBinding {
+  id: string;
+  /** E.g. `Control+Alt+Q`; `code:KeyZ` names a key by position, `char:z` by what it types. */
+  shortcut: string;
+  on_conflict?: SystemAgentConflictPolicy;
+  /** Defaults to `['pressed']`; include `'released'` to get `hotkey_released` with `held_ms`. */
+  events?: ('pressed' | 'released')[];
+  /** `'press'` (default), `'tap'`, or `'hold:<ms>'` for a long press. */
+  trigger?: 'press' | 'tap' | `hold:${number}`;
+  /** Fire on this many quick taps instead (at least 2); `shortcut` may then be modifiers alone. */
+  taps?: number;
+  /** With `taps`: longest tap and longest pause between taps. Defaults to 300. */
+  within_ms?: number;
+  /**
+   * Only fire, and only grab the shortcut, while the focused window matches (X11),
+   * or while an expression over the context holds, e.g. `"mode == 'spellbook' && !spellRunning"`.
+   */
+  when?: SystemAgentWindowScope | string;
+}
+
+/** Windows a hotkey is limited to; every condition given must hold. */
+export interface SystemAgentWindowScope {
+  /** Either part of `WM_CLASS`, ignoring case. */
+  class?: string;
+  /** Process name, as in `/proc/<pid>/comm`, ignoring case. */
+  process?: string;
+  /** Regular expression searched for in the title; `(?i)` ignores case. */
+  title?: string;
+}
+
+/** One entry of the `list_hotkeys` reply. */
+export interface SystemAgentHotkeyInfo {
+  id: string;
+  shortcut: string;
+  normalized: string;
+  /** Unix time in milliseconds. */
+  registered_at: number;
+  fire_count: number;
+  enabled: boolean;
+}
+
+/** One entry of the `sync_hotkeys` report. */
+export interface SystemAgentSyncChange {
+  id: string;
+  action: 'added' | 'removed' | 'updated' | 'unchanged' | 'failed';
+  error?: string;
+}
+
+/** The `normalize_shortcut` reply: the canonical form, or where the shortcut goes wrong. */
+export type SystemAgentShortcutCheck =
+  | { ok: true; normalized: string }
+  | { ok: false; error: string; token: string; position: number; suggestions: string[] };
+
+/** The `status` reply. */
+export interface SystemAgentStatus {
+  agent_version: string;
+  backend: string;
+  uptime_ms: number;
+  clients: number;
+  subscribers: Partial<Record<SystemAgentTopic, number>>;
+  hotkeys: number;
+  queued_events: number;
+  paused: boolean;
+}
+
+/** Protocol version this service was written against. */
+export const SYSTEM_AGENT_PROTOCOL_VERSION = 1;
+
+/** How long `stop()` waits for the agent to exit before killing it. */
+const SYSTEM_AGENT_STOP_TIMEOUT_MS = 3000;
+
+export interface SystemAgentInfo {
+  type: 'ready';
+  protocol_version: number;
+  agent_version: string;
+  backend: 'x11' | 'wayland' | 'none' | string;
+  commands: string[];
+  events: string[];
+}
+
+/** Event groups a client can subscribe to; `hotkeys` and `errors` are on by default. */
+export type SystemAgentTopic = 'hotkeys' | 'raw_keys' | 'errors' | 'clipboard';
+
+interface PendingRequest {
+  resolve: (result: SystemAgentResult) => void;
+  reject: (error: Error) => void;
+}
+
 export class SystemAgentService extends EventEmitter {
   private agentProcess: ChildProcess | null = null;
   private buffer = '';
+  private nextRequestId = 1;
+  private pendingRequests: Map<string, PendingRequest> = new Map();
+  /** Populated from the agent's `ready` event once it has started. */
+  public agentInfo: SystemAgentInfo | null = null;
 
   constructor() {
     super();
@@ -31,26 +142,39 @@ export class SystemAgentService extends EventEmitter {
   public stop(): void {
     if (this.agentProcess) {
       console.log('[SystemAgentEngine] Stopping system-agent process...');
-      this.agentProcess.kill();
-    this.agentProcess = null;
-  }
+      const agent = this.agentProcess;
+      // Closing stdin makes the agent release its grabs and exit on its own
+      agent.stdin?.end();
+      const killTimer = setTimeout(() => agent.kill(), SYSTEM_AGENT_STOP_TIMEOUT_MS);
+      agent.once('close', () => clearTimeout(killTimer));
+      this.agentProcess = null;
+    }
   }
 
   private handleStdout(data: Buffer): void {
-    console.log(`[system-agent-stdout]: ${data.toString().trim()}`);
     const messages = data.toString().trim().split('\n');
     for (const message of messages) {
       if (message) {
       try {
         const event: SystemAgentEvent = JSON.parse(message);
+        const eventName = event.type ?? event.event;
+        // Never log raw keystrokes
+        if (eventName !== 'key_press' && eventName !== 'key_release' && !event.event_type) {
+          console.log(`[system-agent-stdout]: ${message}`);
+        }
+        if (eventName === 'result') {
+          this.settleRequest(event as SystemAgentResult);
+        } else if (eventName === 'ready') {
+          this.handleReady(event as SystemAgentInfo);
+        }
         this.emit('event', event); // A generic event
         // Emit specific events based on the payload
-        if (event.event) {
-          this.emit(event.event, event);
-        } else if (event.event_type) {
-          // Legacy or different event format
-           const eventName = event.event_type.toLowerCase().replace(/_/g, '-');
+        if (eventName) {
           this.emit(eventName, event);
+        } else if (event.event_type) {
+          // Legacy raw key format (--legacy-events): KeyPress/KeyRelease
+          const legacyName = event.event_type === 'KeyPress' ? 'key_press' : 'key_release';
+          this.emit(legacyName, event);
         }
       } catch (err) {
         console.error('Error parsing message from system agent:', message, err);
@@ -68,7 +192,83 @@ export class SystemAgentService extends EventEmitter {
     console.log(`[system-agent-stderr]: ${errorMessage.trim()}`);
   }
 
-  public registerHotkey(shortcut: string, id: string): void {
+  /**
+   * Sends a command tagged with a fresh request_id and resolves once the agent
+   * reports that it has been applied. Rejects if the agent reports a failure.
+   */
+  private sendCommand(command: Record<string, unknown>): Promise<SystemAgentResult> {
+    if (!this.agentProcess) {
+      return Promise.reject(new Error('System Agent is not running.'));
+    }
+
+    const request_id = String(this.nextRequestId++);
+    return new Promise((resolve, reject) => {
+      this.pendingRequests.set(request_id, { resolve, reject });
+      this.agentProcess?.stdin?.write(JSON.stringify({ ...command, request_id }) + '\n');
+    });
+  }
+
+  private handleReady(info: SystemAgentInfo): void {
+    this.agentInfo = info;
+    if (info.protocol_version !== SYSTEM_AGENT_PROTOCOL_VERSION) {
+      const message = `System agent speaks protocol ${info.protocol_version} (agent ${info.agent_version}), expected ${SYSTEM_AGENT_PROTOCOL_VERSION}`;
+      console.error(`[SystemAgentEngine] ${message}`);
+      this.emit('error', { message });
+    }
+  }
+
+  /** Whether the running agent advertised support for the given command. */
+  public supportsCommand(command: string): boolean {
+    return this.agentInfo?.commands.includes(command) ?? false;
+  }
+
+  private settleRequest(result: SystemAgentResult): void {
+    const pending = this.pendingRequests.get(result.request_id);
+    if (!pending) {
+      return;
+    }
+    this.pendingRequests.delete(result.request_id);
+    if (result.ok) {
+      pending.resolve(result);
+    } else {
+      // Keep the full result around, failed commands may still carry a report
+      pending.reject(Object.assign(new Error(result.error ?? 'System agent command failed'), { result }));
+    }
+  }
+
+  public async subscribe(topics: SystemAgentTopic[]): Promise<void> {
+    await this.sendCommand({ command: 'subscribe', topics });
+  }
+
+  public async unsubscribe(topics: SystemAgentTopic[]): Promise<void> {
+    await this.sendCommand({ command: 'unsubscribe', topics });
+  }
+
+  /** The hotkeys this service has registered, as the agent sees them. */
+  public async listHotkeys(): Promise<SystemAgentHotkeyInfo[]> {
+    const result = await this.sendCommand({ command: 'list_hotkeys' });
+    return result.data.hotkeys;
+  }
+
+  /** Validates a shortcut without registering it, e.g. while the user types it. */
+  public async normalizeShortcut(shortcut: string): Promise<SystemAgentShortcutCheck> {
+    try {
+      const result = await this.sendCommand({ command: 'normalize_shortcut', shortcut });
+      return { ok: true, normalized: result.data.normalized };
+    } catch (err: any) {
+      if (err.result?.data) {
+        return { ok: false, error: err.result.error, ...err.result.data };
+      }
+      throw err;
+    }
+  }
+
+  public async getStatus(): Promise<SystemAgentStatus> {
+    const result = await this.sendCommand({ command: 'status' });
+    return result.data;
+  }
+
+  public async registerHotkey(shortcut: string, id: string, onConflict?: SystemAgentConflictPolicy): Promise<void> {
     if (!this.agentProcess) {
       console.error('Cannot register hotkey: System Agent is not running.');
       return;
@@ -78,13 +278,14 @@ export class SystemAgentService extends EventEmitter {
       command: 'register',
       shortcut: shortcut,
       id: id,
+      on_conflict: onConflict,
     };
 
-    this.agentProcess.stdin?.write(JSON.stringify(command) + '\n');
-    console.log(`Sent command to register hotkey "${shortcut}" with id "${id}"`);
+    console.log(`Sending command to register hotkey "${shortcut}" with id "${id}"`);
+    await this.sendCommand(command);
   }
 
-  public unregisterHotkey(shortcut: string, id: string): void {
+  public async unregisterHotkey(shortcut: string, id: string): Promise<void> {
     if (!this.agentProcess) {
       // It's okay if the agent is not running, we just can't send the command.
       return;
@@ -95,19 +296,23 @@ export class SystemAgentService extends EventEmitter {
       shortcut: shortcut,
       id: id,
     };
-    this.agentProcess.stdin?.write(JSON.stringify(command) + '\n');
+    await this.sendCommand(command);
   }
 
-  public unregisterAllHotkeys(): void {
+  public async unregisterAllHotkeys(): Promise<void> {
     if (!this.agentProcess) {
       return;
     }
     console.log('Sending command to unregister all hotkeys');
     const command = { command: 'unregister_all' };
-    this.agentProcess.stdin?.write(JSON.stringify(command) + '\n');
+    await this.sendCommand(command);
   }
 
-  public registerHotkeys(bindings: { id: string; shortcut: string }[]): void {
+  /**
+   * Registers the bindings in one command. With `atomic`, either all of them
+   * are registered or none are and the previous registrations stay in place.
+   */
+  public async registerHotkeys(bindings: SystemAgentBinding[], atomic = false): Promise<void> {
     if (!this.agentProcess) {
       console.error('Cannot register hotkeys: System Agent is not running.');
       return;
@@ -116,10 +321,91 @@ export class SystemAgentService extends EventEmitter {
     const command = {
       command: 'register_batch',
       hotkeys: bindings,
+      atomic,
     };
 
-    this.agentProcess.stdin?.write(JSON.stringify(command) + '\n');
-    console.log(`Sent command to register ${bindings.length} hotkeys in batch`);
+    console.log(`Sending command to register ${bindings.length} hotkeys in batch`);
+    await this.sendCommand(command);
+  }
+
+  /**
+   * Makes the agent's hotkeys exactly `bindings`, releasing and grabbing only
+   * what changed. Resolves with the per-hotkey report even if some failed.
+   */
+  public async syncHotkeys(bindings: SystemAgentBinding[]): Promise<SystemAgentSyncChange[]> {
+    if (!this.agentProcess) {
+      console.error('Cannot sync hotkeys: System Agent is not running.');
+      return [];
+    }
+
+    console.log(`Sending command to sync ${bindings.length} hotkeys`);
+    try {
+      const result = await this.sendCommand({ command: 'sync_hotkeys', hotkeys: bindings });
+      return result.data.changes;
+    } catch (err: any) {
+      if (err.result?.data) {
+        return err.result.data.changes;
+      }
+      throw err;
+    }
+  }
+
+  /** Stores a named layer of bindings; nothing is grabbed until it is pushed. */
+  public async defineLayer(name: string, bindings: SystemAgentBinding[]): Promise<void> {
+    await this.sendCommand({ command: 'define_layer', name, hotkeys: bindings });
+  }
+
+  /**
+   * Activates a layer on top of the current bindings, shadowing those with the
+   * same id or shortcut. Resolves with the active layers, bottom first.
+   */
+  public async pushLayer(name: string): Promise<string[]> {
+    const result = await this.sendCommand({ command: 'push_layer', name });
+    return result.data.layers;
+  }
+
+  /** Deactivates the top layer, which must be `name` if given. Resolves with the active layers. */
+  public async popLayer(name?: string): Promise<string[]> {
+    const result = await this.sendCommand({ command: 'pop_layer', name });
+    return result.data.layers;
+  }
+
+  /**
+   * Grabs the whole keyboard; keys arrive as `captured_key` events until
+   * `endCapture()`, Esc, or `timeoutMs` without a key press (`capture_ended`).
+   */
+  public async beginCapture(timeoutMs?: number): Promise<void> {
+    await this.sendCommand({ command: 'begin_capture', timeout_ms: timeoutMs });
+  }
+
+  public async endCapture(): Promise<void> {
+    await this.sendCommand({ command: 'end_capture' });
+  }
+
+  /**
+   * Releases every grab, keeping the registrations, until `resumeAll()`.
+   * `except` is a hotkey that stays active meanwhile, e.g. to resume.
+   */
+  public async pauseAll(except?: string): Promise<void> {
+    await this.sendCommand({ command: 'pause_all', except });
+  }
+
+  public async resumeAll(): Promise<void> {
+    await this.sendCommand({ command: 'resume_all' });
+  }
+
+  /** Releases one hotkey's grab, keeping its registration, until `enable(id)`. */
+  public async disable(id: string): Promise<void> {
+    await this.sendCommand({ command: 'disable', id });
+  }
+
+  public async enable(id: string): Promise<void> {
+    await this.sendCommand({ command: 'enable', id });
+  }
+
+  /** Sets keys read by `when` expressions; `null` removes a key. */
+  public async setContext(values: Record<string, string | number | boolean | null>): Promise<void> {
+    await this.sendCommand({ command: 'set_context', values });
   }
 
   private handleClose(code: number): void {
@@ -130,6 +416,11 @@ export class SystemAgentService extends EventEmitter {
     } else {
       console.log('System agent exited gracefully.');
     }
+    for (const pending of this.pendingRequests.values()) {
+      pending.reject(new Error('System agent exited before replying'));
+    }
+    this.pendingRequests.clear();
+    this.agentInfo = null;
     this.agentProcess = null;
   }
 } 
\ No newline at end of file
@@ -6,6 +6,9 @@ console.log('Press Control+Alt+T to test, then Ctrl+C to exit.\n');
 
 const agent = spawn('./target/release/system-agent', [], { stdio: ['pipe', 'pipe', 'inherit'] });
 
+// Opt in to the raw key stream so leaked keys can be detected below
+agent.stdin.write(JSON.stringify({ command: 'subscribe', topics: ['raw_keys'] }) + '\n');
+
 // Register the hotkey
 const registerCmd = JSON.stringify({ command: 'register', id: 'manual-test', shortcut: 'Control+Alt+T' }) + '\n';
 agent.stdin.write(registerCmd);
@@ -20,9 +23,9 @@ agent.stdout.on('data', (data) => {
         if (line) {
             try {
                 const event = JSON.parse(line);
-                if (event.event === 'hotkey_pressed') {
+                if (event.type === 'hotkey_pressed') {
                     console.log('🎉 HOTKEY EVENT:', event);
-                } else if (event.event_type === 'KeyPress' && event.key === 'KeyT') {
+                } else if (event.type === 'key_press' && event.key === 'KeyT') {
                     console.log('⚠️  Raw T key detected - this should NOT happen when Control+Alt+T is registered!');
                 }
             } catch (e) {
@@ -1,100 +1,210 @@
-use crate::cmd::{Command, RegisterCommand};
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use global_hotkey::{
-    hotkey::HotKey,
-    GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState,
-};
+use crate::client::{ClientId, Clients};
+use crate::cmd::{Command, ContextValue, RegisterCommand, Request, Topic, COMMANDS, PROTOCOL_VERSION};
+use crate::event::{self, Event, HotkeyFailure, ResultData, ShutdownReason, EVENTS};
+use crate::hotkey;
+use crate::layout;
+use crate::registry::HotkeyRegistry;
+use crate::window;
+use crossbeam_channel::{never, select, unbounded, Receiver, Sender};
+use global_hotkey::{GlobalHotKeyEvent, GlobalHotKeyManager};
 use rdev::{listen, EventType, Key};
-use serde::Serialize;
-use serde_json::Deserializer;
-use std::collections::HashMap;
-use std::io::{self, stdout, Write};
+use serde_json::{Deserializer, Value};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long clients get to drain their output once shutdown has started. The
+/// process exits regardless when this runs out.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the hotkey thread checks for shutdown while nothing else happens.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often stdio mode checks whether the parent process is still alive.
+#[cfg(unix)]
+const PARENT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A command for the hotkey manager, tagged with the client it came from.
+#[derive(Debug)]
+pub(crate) struct ManagerRequest {
+    client: ClientId,
+    request_id: Option<String>,
+    command: HotkeyManagerCommand,
+}
 
 #[derive(Debug)]
 enum HotkeyManagerCommand {
-    Register { id: String, shortcut: String },
+    Register(RegisterCommand),
     Unregister { id: String, shortcut: String },
-    RegisterBatch { hotkeys: Vec<(String, String)> },
+    RegisterBatch { hotkeys: Vec<RegisterCommand>, atomic: bool },
+    SyncHotkeys { hotkeys: Vec<RegisterCommand> },
     UnregisterAll,
+    DefineLayer { name: String, hotkeys: Vec<RegisterCommand> },
+    PushLayer { name: String },
+    PopLayer { name: Option<String> },
+    BeginCapture { timeout_ms: Option<u64> },
+    EndCapture,
+    SetContext { values: BTreeMap<String, Option<ContextValue>> },
+    PauseAll { except: Option<String> },
+    ResumeAll,
+    SetEnabled { id: String, enabled: bool },
+    ListHotkeys,
+    Status,
+    /// The client went away; release everything it registered.
+    Disconnect,
 }
 
-#[derive(Serialize, Debug)]
-struct OutputEvent<'a> {
-    event: &'a str,
-    id: &'a str,
-}
-
-#[derive(Serialize, Debug)]
-struct RawKeyEvent<'a> {
-    event_type: &'a str,
-    key: String,
+impl ManagerRequest {
+    pub(crate) fn disconnect(client: ClientId) -> Self {
+        Self { client, request_id: None, command: HotkeyManagerCommand::Disconnect }
+    }
 }
 
-#[derive(Serialize, Debug)]
-struct ErrorEvent<'a> {
-    event: &'a str,
-    message: String,
-    context: &'a str,
+/// Startup options, parsed from the command line in `main`.
+#[derive(Debug, Default)]
+pub struct AgentOptions {
+    /// Emit the pre-envelope event shapes instead of `{"v":1,"type":...}`.
+    pub legacy_events: bool,
+    /// Serve any number of clients on this Unix socket instead of stdin/stdout.
+    pub socket_path: Option<PathBuf>,
 }
 
 pub struct SystemAgent {
     running: Arc<AtomicBool>,
+    options: AgentOptions,
+    /// Anything that wants the agent to stop sends the reason here; `run`
+    /// acts on the first one.
+    shutdown_sender: Sender<ShutdownReason>,
+    shutdown_receiver: Receiver<ShutdownReason>,
+    /// Taken as early as possible, so a parent that dies during startup is still noticed.
+    #[cfg(unix)]
+    parent: u32,
 }
 
 impl SystemAgent {
-    pub fn new() -> Self {
+    pub fn new(options: AgentOptions) -> Self {
+        event::start_clock();
+        event::set_legacy_format(options.legacy_events);
+
         let running = Arc::new(AtomicBool::new(true));
-        let r = running.clone();
+        let (shutdown_sender, shutdown_receiver) = unbounded();
+        let s = shutdown_sender.clone();
         ctrlc::set_handler(move || {
-            r.store(false, Ordering::SeqCst);
+            let _ = s.send(ShutdownReason::Signal);
         })
         .expect("Error setting Ctrl-C handler");
 
         Self {
             running,
+            options,
+            shutdown_sender,
+            shutdown_receiver,
+            #[cfg(unix)]
+            parent: std::os::unix::process::parent_id(),
         }
     }
 
     pub fn run(&self) {
         eprintln!("[system-agent] Starting hybrid hotkey system (registration + streaming)...");
         
-        // Create channel for communication between command listener and hotkey manager
-        let (cmd_sender, cmd_receiver) = unbounded::<HotkeyManagerCommand>();
+        let clients = Arc::new(Clients::default());
+        
+        // Create channel for communication between command listeners and hotkey manager
+        let (cmd_sender, cmd_receiver) = unbounded::<ManagerRequest>();
+        // And for raw key and click events, which tap hotkeys and captured keys are read from
+        let (key_sender, key_receiver) = unbounded::<(Instant, rdev::Event)>();
         
         // Thread 1: Hotkey Registration and Command Handling
         let running_clone1 = self.running.clone();
+        let clients_clone1 = clients.clone();
         let hotkey_thread = thread::spawn(move || {
-            hotkey_registration_thread(cmd_receiver, running_clone1);
+            hotkey_registration_thread(cmd_receiver, key_receiver, clients_clone1, running_clone1);
         });
 
         // Thread 2: Raw Key Event Streaming
         let running_clone2 = self.running.clone();
+        let clients_clone2 = clients.clone();
         let rdev_thread = thread::spawn(move || {
-            raw_key_streaming_thread(running_clone2);
+            raw_key_streaming_thread(key_sender, clients_clone2, running_clone2);
         });
 
-        // Thread 3: Command Listener (reads from stdin)
+        // Thread 3: Command Listener (reads from stdin, or accepts socket clients)
         let running_clone3 = self.running.clone();
-        let stdin_thread = thread::spawn(move || {
-            command_listener(cmd_sender, running_clone3);
-        });
+        let clients_clone3 = clients.clone();
+        let shutdown_clone3 = self.shutdown_sender.clone();
+        let socket_thread = match self.options.socket_path.clone() {
+            #[cfg(unix)]
+            Some(path) => Some(thread::spawn(move || {
+                crate::server::serve(&path, cmd_sender, shutdown_clone3, clients_clone3, running_clone3);
+            })),
+            #[cfg(not(unix))]
+            Some(_) => panic!("--socket is only supported on Unix platforms"),
+            None => {
+                thread::spawn(move || {
+                    let hangup = shutdown_clone3.clone();
+                    let client = clients_clone3.connect(
+                        Box::new(io::stdout()),
+                        Box::new(move || {
+                            // Without its only client the agent would hold grabs nobody hears about
+                            let _ = hangup.send(ShutdownReason::Error);
+                        }),
+                    );
+                    // Announce ourselves before anything else so the parent can check compatibility
+                    send_ready(&clients_clone3, client);
+                    command_listener(client, io::stdin().lock(), &cmd_sender, &shutdown_clone3, &clients_clone3, &running_clone3);
+                    let _ = shutdown_clone3.send(ShutdownReason::StdinClosed);
+                });
+
+                // Thread 4: Parent watch, so a crashed parent does not leave us holding grabs
+                #[cfg(unix)]
+                {
+                    let running_clone4 = self.running.clone();
+                    let shutdown_clone4 = self.shutdown_sender.clone();
+                    let parent = self.parent;
+                    thread::spawn(move || {
+                        parent_watch_thread(parent, shutdown_clone4, running_clone4);
+                    });
+                }
+                None
+            }
+        };
 
-        eprintln!("[system-agent] All threads started. Waiting for completion...");
+        eprintln!("[system-agent] All threads started. Waiting for shutdown...");
+        
+        let reason = self.shutdown_receiver.recv().expect("Shutdown channel closed");
+        eprintln!("[system-agent] Shutting down ({:?})...", reason);
+        self.running.store(false, Ordering::SeqCst);
+        
+        // Neither rdev's listen() nor a blocking read on stdin can be interrupted,
+        // so those threads are left behind and end with the process. Bound the
+        // time spent on the rest in case a client stops reading its output.
+        thread::spawn(|| {
+            thread::sleep(SHUTDOWN_TIMEOUT);
+            eprintln!("[system-agent] Shutdown timed out, exiting anyway.");
+            std::process::exit(1);
+        });
         
-        // Wait for all threads to complete
-        stdin_thread.join().expect("Stdin thread panicked");
+        // The hotkey thread releases every grab before it exits
         hotkey_thread.join().expect("Hotkey thread panicked");
-        rdev_thread.join().expect("Rdev thread panicked");
+        if let Some(socket_thread) = socket_thread {
+            socket_thread.join().expect("Socket thread panicked");
+        }
+        drop(rdev_thread);
+        
+        // Last word to every client, then flush whatever is still queued for them
+        clients.broadcast(&Event::ShuttingDown { reason });
+        clients.shutdown();
         
         eprintln!("[system-agent] Shutdown complete.");
     }
 }
 
 fn hotkey_registration_thread(
-    command_receiver: Receiver<HotkeyManagerCommand>,
+    command_receiver: Receiver<ManagerRequest>,
+    key_receiver: Receiver<(Instant, rdev::Event)>,
+    clients: Arc<Clients>,
     running: Arc<AtomicBool>,
 ) {
     eprintln!("[system-agent] Hotkey registration thread starting...");
@@ -105,8 +215,7 @@ fn hotkey_registration_thread(
         Err(e) => {
             let msg = format!("Failed to initialize global hotkey manager: {}", e);
             eprintln!("[system-agent] {}", msg);
-            send_event(&ErrorEvent { 
-                event: "error", 
+            clients.broadcast(&Event::Error { 
                 message: msg, 
                 context: "hotkey_manager_init" 
             });
@@ -115,234 +224,195 @@ fn hotkey_registration_thread(
     };
     
     // Keep track of registered hotkeys for cleanup and ID mapping
-    let mut registered_hotkeys: HashMap<String, HotKey> = HashMap::new();
-    let mut id_mapping: HashMap<u32, String> = HashMap::new(); // Map global-hotkey numeric IDs to our string IDs
-    
-    // Create shared data for the event thread using Arc + Mutex
-    let id_mapping_shared = Arc::new(std::sync::Mutex::new(HashMap::<u32, String>::new()));
-    let id_mapping_clone = id_mapping_shared.clone();
-    
-    // Start hotkey event listener in a separate thread
-    let running_clone = running.clone();
-    let event_thread = thread::spawn(move || {
-        eprintln!("[system-agent] Hotkey event listener starting...");
-        
-        while running_clone.load(Ordering::SeqCst) {
-            if let Ok(event) = GlobalHotKeyEvent::receiver().try_recv() {
-                eprintln!("[system-agent] Global hotkey triggered: {:?}", event);
-                
-                // Only process "Pressed" state to avoid duplicate events
-                if event.state == HotKeyState::Pressed {
-                    // Look up the original string ID from our mapping
-                    let id = {
-                        let mapping = id_mapping_clone.lock().unwrap();
-                        mapping.get(&event.id).cloned()
-                    };
-                    
-                    if let Some(original_id) = id {
-                        eprintln!("[system-agent] Sending hotkey_pressed event for: {}", original_id);
-                        send_event(&OutputEvent { 
-                            event: "hotkey_pressed", 
-                            id: &original_id 
-                        });
-                    } else {
-                        eprintln!("[system-agent] Warning: Received hotkey event for unknown ID: {}", event.id);
-                    }
-                }
-            }
-            
-            // Small delay to prevent busy waiting
-            thread::sleep(std::time::Duration::from_millis(10));
-        }
-        
-        eprintln!("[system-agent] Hotkey event listener exited.");
-    });
+    let (layout, layout_changes) = layout::watch();
+    let (window, focus_changes) = window::watch();
+    let mut registry = HotkeyRegistry::new(manager, clients.clone(), layout, window);
     
-    // Process commands from the command listener thread
+    // Commands and hotkey presses are handled on this one thread, so the
+    // registry needs no locking and queries always see a consistent state.
+    let mut command_receiver = command_receiver;
+    let mut key_receiver = key_receiver;
+    let mut layout_changes = layout_changes;
+    let mut focus_changes = focus_changes;
+    let hotkey_events = GlobalHotKeyEvent::receiver();
     while running.load(Ordering::SeqCst) {
-        if let Ok(command) = command_receiver.recv_timeout(std::time::Duration::from_micros(100)) {
-            match command {
-                HotkeyManagerCommand::Register { id, shortcut } => {
-                    match parse_hotkey(&shortcut) {
-                        Ok(hotkey) => {
-                            // Get the hotkey ID before registering
-                            let hotkey_id = hotkey.id();
-                            
-                            match manager.register(hotkey) {
-                                Ok(()) => {
-                                    eprintln!("[system-agent] Successfully registered global hotkey: {} -> {} (ID: {})", id, shortcut, hotkey_id);
-                                    registered_hotkeys.insert(id.clone(), hotkey);
-                                    
-                                    // Store the ID mapping for event lookup
-                                    {
-                                        let mut mapping = id_mapping_shared.lock().unwrap();
-                                        mapping.insert(hotkey_id, id.clone());
-                                    }
-                                    id_mapping.insert(hotkey_id, id.clone());
-                                }
-                                Err(e) => {
-                                    let msg = format!("Failed to register global hotkey {}: {}", shortcut, e);
-                                    eprintln!("[system-agent] {}", msg);
-                                    send_event(&ErrorEvent { 
-                                        event: "error", 
-                                        message: msg, 
-                                        context: "hotkey_register" 
-                                    });
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            let msg = format!("Failed to parse hotkey {}: {}", shortcut, e);
-                            eprintln!("[system-agent] {}", msg);
-                            send_event(&ErrorEvent { 
-                                event: "error", 
-                                message: msg, 
-                                context: "hotkey_parse" 
-                            });
-                        }
-                    }
-                }
-                HotkeyManagerCommand::Unregister { id, shortcut: _shortcut } => {
-                    if let Some(hotkey) = registered_hotkeys.remove(&id) {
-                        let hotkey_id = hotkey.id();
-                        
-                        match manager.unregister(hotkey) {
-                            Ok(()) => {
-                                eprintln!("[system-agent] Successfully unregistered global hotkey: {} (ID: {})", id, hotkey_id);
-                                
-                                // Remove from ID mapping
-                                {
-                                    let mut mapping = id_mapping_shared.lock().unwrap();
-                                    mapping.remove(&hotkey_id);
-                                }
-                                id_mapping.remove(&hotkey_id);
-                            }
-                            Err(e) => {
-                                let msg = format!("Failed to unregister global hotkey {}: {}", id, e);
-                                eprintln!("[system-agent] {}", msg);
-                                send_event(&ErrorEvent { 
-                                    event: "error", 
-                                    message: msg, 
-                                    context: "hotkey_unregister" 
-                                });
-                            }
-                        }
-                    } else {
-                        eprintln!("[system-agent] Warning: Attempted to unregister unknown hotkey: {}", id);
-                    }
-                }
-                HotkeyManagerCommand::RegisterBatch { hotkeys } => {
-                    eprintln!("[system-agent] Processing register_batch with {} hotkeys", hotkeys.len());
-                    
-                    for (id, shortcut) in hotkeys {
-                        match parse_hotkey(&shortcut) {
-                            Ok(hotkey) => {
-                                // Get the hotkey ID before registering
-                                let hotkey_id = hotkey.id();
-                                
-                                match manager.register(hotkey) {
-                                    Ok(()) => {
-                                        eprintln!("[system-agent] Successfully registered global hotkey: {} -> {} (ID: {})", id, shortcut, hotkey_id);
-                                        registered_hotkeys.insert(id.clone(), hotkey);
-                                        
-                                        // Store the ID mapping for event lookup
-                                        {
-                                            let mut mapping = id_mapping_shared.lock().unwrap();
-                                            mapping.insert(hotkey_id, id.clone());
-                                        }
-                                        id_mapping.insert(hotkey_id, id.clone());
-                                    }
-                                    Err(e) => {
-                                        let msg = format!("Failed to register global hotkey {}: {}", shortcut, e);
-                                        eprintln!("[system-agent] {}", msg);
-                                        send_event(&ErrorEvent { 
-                                            event: "error", 
-                                            message: msg, 
-                                            context: "hotkey_register_batch" 
-                                        });
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                let msg = format!("Failed to parse hotkey {}: {}", shortcut, e);
-                                eprintln!("[system-agent] {}", msg);
-                                send_event(&ErrorEvent { 
-                                    event: "error", 
-                                    message: msg, 
-                                    context: "hotkey_parse_batch" 
-                                });
-                            }
-                        }
-                    }
-                }
-                HotkeyManagerCommand::UnregisterAll => {
-                    eprintln!("[system-agent] Processing unregister_all command");
-                    
-                    // Unregister all hotkeys from the manager
-                    for (id, hotkey) in registered_hotkeys.drain() {
-                        let hotkey_id = hotkey.id();
-                        
-                        match manager.unregister(hotkey) {
-                            Ok(()) => {
-                                eprintln!("[system-agent] Successfully unregistered global hotkey: {} (ID: {})", id, hotkey_id);
-                            }
-                            Err(e) => {
-                                let msg = format!("Failed to unregister global hotkey {}: {}", id, e);
-                                eprintln!("[system-agent] {}", msg);
-                                send_event(&ErrorEvent { 
-                                    event: "error", 
-                                    message: msg, 
-                                    context: "hotkey_unregister_all" 
-                                });
-                            }
-                        }
-                    }
-                    
-                    // Clear ID mappings
-                    {
-                        let mut mapping = id_mapping_shared.lock().unwrap();
-                        mapping.clear();
-                    }
-                    id_mapping.clear();
+        // Wake up in time for the next hold trigger, and regularly to notice shutdown
+        let timeout = registry
+            .next_deadline()
+            .map_or(IDLE_POLL_INTERVAL, |deadline| deadline.saturating_duration_since(Instant::now()).min(IDLE_POLL_INTERVAL));
+        select! {
+            recv(command_receiver) -> request => match request {
+                Ok(request) => handle_manager_request(&mut registry, request),
+                // Every command listener is gone, but grabbed hotkeys still fire
+                Err(_) => command_receiver = never(),
+            },
+            recv(hotkey_events) -> event => {
+                if let Ok(event) = event {
+                    registry.dispatch(event);
                 }
             }
+            recv(key_receiver) -> event => match event {
+                Ok((at, event)) => registry.key_event(at, event),
+                // No raw key stream on this system, so tap hotkeys never fire
+                Err(_) => key_receiver = never(),
+            },
+            recv(layout_changes) -> layout => match layout {
+                Ok(layout) => registry.layout_changed(layout),
+                Err(_) => layout_changes = never(),
+            },
+            recv(focus_changes) -> window => match window {
+                Ok(window) => registry.focus_changed(window),
+                Err(_) => focus_changes = never(),
+            },
+            default(timeout) => {}
         }
-        // Note: recv_timeout already provides the necessary delay, no need for additional sleep
+        registry.tick(Instant::now());
     }
     
-    // Cleanup: unregister all hotkeys
-    eprintln!("[system-agent] Cleaning up registered hotkeys...");
-    for (id, hotkey) in registered_hotkeys {
-        let hotkey_id = hotkey.id();
-        if let Err(e) = manager.unregister(hotkey) {
-            eprintln!("[system-agent] Failed to unregister hotkey {} (ID: {}) during cleanup: {}", id, hotkey_id, e);
-        } else {
-            eprintln!("[system-agent] Cleaned up hotkey: {} (ID: {})", id, hotkey_id);
-        }
+    // Apply what was sent right before shutdown, so every request still gets its result
+    for request in command_receiver.try_iter() {
+        handle_manager_request(&mut registry, request);
     }
     
-    event_thread.join().expect("Event thread panicked");
+    // Cleanup: unregister all hotkeys
+    registry.clear();
+    
     eprintln!("[system-agent] Hotkey registration thread exited.");
 }
 
-fn raw_key_streaming_thread(running: Arc<AtomicBool>) {
+fn handle_manager_request(registry: &mut HotkeyRegistry, request: ManagerRequest) {
+    let ManagerRequest { client, request_id, command } = request;
+    let (result, data) = match command {
+        HotkeyManagerCommand::Register(binding) => {
+            (registry.register(client, &binding, "hotkey_register"), None)
+        }
+        HotkeyManagerCommand::Unregister { id, shortcut: _shortcut } => {
+            (registry.unregister(client, &id), None)
+        }
+        HotkeyManagerCommand::RegisterBatch { hotkeys, atomic } => {
+            eprintln!("[system-agent] Processing register_batch with {} hotkeys (atomic: {})", hotkeys.len(), atomic);
+            
+            let failures = if atomic {
+                registry.register_batch_atomic(client, hotkeys)
+            } else {
+                hotkeys
+                    .into_iter()
+                    .filter_map(|binding| {
+                        let error = registry.register(client, &binding, "hotkey_register_batch").err()?;
+                        Some(HotkeyFailure { id: binding.id, shortcut: binding.shortcut, error })
+                    })
+                    .collect()
+            };
+            if failures.is_empty() {
+                (Ok(()), None)
+            } else {
+                let summary: Vec<&str> = failures.iter().map(|failure| failure.error.as_str()).collect();
+                let error = if atomic {
+                    format!("Batch rolled back: {}", summary.join("; "))
+                } else {
+                    summary.join("; ")
+                };
+                (Err(error), Some(ResultData::Failures { failures }))
+            }
+        }
+        HotkeyManagerCommand::SyncHotkeys { hotkeys } => {
+            eprintln!("[system-agent] Processing sync_hotkeys with {} hotkeys", hotkeys.len());
+            
+            let changes = registry.sync(client, hotkeys);
+            let failures: Vec<String> = changes
+                .iter()
+                .filter_map(|change| change.error.as_ref().map(|e| format!("{}: {}", change.id, e)))
+                .collect();
+            let result = if failures.is_empty() { Ok(()) } else { Err(failures.join("; ")) };
+            // The report is sent even on failure so the client knows what did change
+            (result, Some(ResultData::Sync { changes }))
+        }
+        HotkeyManagerCommand::UnregisterAll => {
+            eprintln!("[system-agent] Processing unregister_all command");
+            (registry.unregister_all(client), None)
+        }
+        HotkeyManagerCommand::DefineLayer { name, hotkeys } => {
+            (registry.define_layer(client, &name, hotkeys), None)
+        }
+        HotkeyManagerCommand::PushLayer { name } => {
+            eprintln!("[system-agent] Processing push_layer {}", name);
+            match registry.push_layer(client, &name) {
+                Ok(failures) if !failures.is_empty() => {
+                    let summary: Vec<&str> = failures.iter().map(|failure| failure.error.as_str()).collect();
+                    (Err(summary.join("; ")), Some(ResultData::Failures { failures }))
+                }
+                Ok(_) => (Ok(()), Some(ResultData::Layers { layers: registry.layers(client) })),
+                Err(e) => (Err(e), None),
+            }
+        }
+        HotkeyManagerCommand::PopLayer { name } => {
+            eprintln!("[system-agent] Processing pop_layer {}", name.as_deref().unwrap_or("(top)"));
+            match registry.pop_layer(client, name.as_deref()) {
+                Ok(()) => (Ok(()), Some(ResultData::Layers { layers: registry.layers(client) })),
+                Err(e) => (Err(e), None),
+            }
+        }
+        HotkeyManagerCommand::BeginCapture { timeout_ms } => {
+            (registry.begin_capture(client, timeout_ms.map(Duration::from_millis)), None)
+        }
+        HotkeyManagerCommand::EndCapture => {
+            (registry.end_capture(client), None)
+        }
+        HotkeyManagerCommand::SetContext { values } => {
+            registry.set_context(client, values);
+            (Ok(()), None)
+        }
+        HotkeyManagerCommand::PauseAll { except } => {
+            (registry.pause_all(client, except.as_deref()), None)
+        }
+        HotkeyManagerCommand::ResumeAll => {
+            (registry.resume_all(client), None)
+        }
+        HotkeyManagerCommand::SetEnabled { id, enabled } => {
+            (registry.set_enabled(client, &id, enabled), None)
+        }
+        HotkeyManagerCommand::ListHotkeys => {
+            (Ok(()), Some(ResultData::Hotkeys { hotkeys: registry.list(client) }))
+        }
+        HotkeyManagerCommand::Status => {
+            (Ok(()), Some(ResultData::Status(registry.status())))
+        }
+        HotkeyManagerCommand::Disconnect => {
+            eprintln!("[system-agent] Releasing hotkeys of disconnected client {}", client);
+            let result = registry.unregister_all(client);
+            registry.forget(client);
+            (result, None)
+        }
+    };
+    send_result(registry.clients(), client, request_id.as_deref(), result, data);
+}
+
+fn raw_key_streaming_thread(key_sender: Sender<(Instant, rdev::Event)>, clients: Arc<Clients>, running: Arc<AtomicBool>) {
     eprintln!("[system-agent] Raw key streaming thread starting...");
     
-    // This thread's only job is to stream raw key events
-    // All the complex hotkey detection logic has been removed
+    // This thread streams raw key events, and hands them to the hotkey thread for tap detection
     if let Err(error) = listen(move |event| {
         if !running.load(Ordering::SeqCst) {
             // This will break the listen closure and cause listen() to return.
             return;
         }
         
+        if matches!(event.event_type, EventType::KeyPress(_) | EventType::KeyRelease(_) | EventType::ButtonPress(_)) {
+            let _ = key_sender.send((Instant::now(), event.clone()));
+        }
+        
+        // Don't even format the key unless some client asked for the raw stream
+        if !clients.wants(Topic::RawKeys) {
+            return;
+        }
+        
         // Simply stream all key events - no hotkey detection needed
         match event.event_type {
             EventType::KeyPress(key) => {
-                send_raw_event("KeyPress", key);
+                send_raw_event(&clients, true, key);
             }
             EventType::KeyRelease(key) => {
-                send_raw_event("KeyRelease", key);
+                send_raw_event(&clients, false, key);
             }
             _ => (), // Ignore other event types
         }
@@ -353,119 +423,258 @@ fn raw_key_streaming_thread(running: Arc<AtomicBool>) {
     eprintln!("[system-agent] Raw key streaming thread exited.");
 }
 
-fn command_listener(
-    hotkey_sender: Sender<HotkeyManagerCommand>, 
-    running: Arc<AtomicBool>
+/// Requests shutdown once the process that started us has exited, which shows
+/// up as being reparented.
+#[cfg(unix)]
+fn parent_watch_thread(parent: u32, shutdown_sender: Sender<ShutdownReason>, running: Arc<AtomicBool>) {
+    while running.load(Ordering::SeqCst) {
+        thread::sleep(PARENT_POLL_INTERVAL);
+        if std::os::unix::process::parent_id() != parent {
+            eprintln!("[system-agent] Parent process {} exited", parent);
+            let _ = shutdown_sender.send(ShutdownReason::ParentExited);
+            break;
+        }
+    }
+}
+
+/// Reads line-delimited commands for one client until its input stream ends.
+pub(crate) fn command_listener(
+    client: ClientId,
+    reader: impl Read,
+    hotkey_sender: &Sender<ManagerRequest>, 
+    shutdown_sender: &Sender<ShutdownReason>,
+    clients: &Clients,
+    running: &AtomicBool,
 ) {
-    eprintln!("[system-agent] Command listener starting...");
+    eprintln!("[system-agent] Command listener for client {} starting...", client);
     
-    let stdin = io::stdin();
-    let stream = Deserializer::from_reader(stdin.lock()).into_iter::<Command>();
+    // Read each line as a generic JSON value first so that the request_id can
+    // still be echoed back when the command itself fails to parse.
+    let stream = Deserializer::from_reader(reader).into_iter::<Value>();
 
-    for cmd_result in stream {
+    for value_result in stream {
         if !running.load(Ordering::SeqCst) {
             break;
         }
         
+        let request_id = value_result
+            .as_ref()
+            .ok()
+            .and_then(|value| value.get("request_id"))
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let cmd_result = value_result.and_then(serde_json::from_value::<Request>);
+
         match cmd_result {
-            Ok(cmd) => handle_command(cmd, &hotkey_sender),
+            Ok(request) => handle_command(client, request, hotkey_sender, shutdown_sender, clients),
             Err(e) => {
                 let msg = format!("Failed to parse command: {}", e);
                 eprintln!("[system-agent] {}", msg);
-                send_event(&ErrorEvent { 
-                    event: "error", 
-                    message: msg, 
+                clients.send(client, &Event::Error { 
+                    message: msg.clone(), 
                     context: "command_parse" 
                 });
+                send_result(clients, client, request_id.as_deref(), Err(msg), None);
             }
         }
     }
     
-    eprintln!("[system-agent] Command listener exited.");
+    eprintln!("[system-agent] Command listener for client {} exited.", client);
 }
 
-fn handle_command(cmd: Command, hotkey_sender: &Sender<HotkeyManagerCommand>) {
-    match cmd {
-        Command::Register(RegisterCommand { id, shortcut }) => {
-            eprintln!("[system-agent] Received register command: {} -> {}", id, shortcut);
+fn handle_command(
+    client: ClientId,
+    request: Request,
+    hotkey_sender: &Sender<ManagerRequest>,
+    shutdown_sender: &Sender<ShutdownReason>,
+    clients: &Clients,
+) {
+    let Request { request_id, command } = request;
+
+    let (name, command) = match command {
+        Command::Hello { protocol_version } => {
+            eprintln!("[system-agent] Received hello command (client protocol: {:?})", protocol_version);
             
-            if let Err(e) = hotkey_sender.send(HotkeyManagerCommand::Register { 
-                id: id.clone(), 
-                shortcut: shortcut.clone() 
-            }) {
-                let msg = format!("Failed to send register command to hotkey manager: {}", e);
-                eprintln!("[system-agent] {}", msg);
-                send_event(&ErrorEvent { 
-                    event: "error", 
-                    message: msg, 
-                    context: "command_send" 
-                });
-            }
+            // Always describe ourselves, even to an incompatible client, so it can report the mismatch
+            send_ready(clients, client);
+            let result = match protocol_version {
+                Some(version) if version != PROTOCOL_VERSION => Err(format!(
+                    "Unsupported protocol version {} (agent speaks {})",
+                    version, PROTOCOL_VERSION
+                )),
+                _ => Ok(()),
+            };
+            send_result(clients, client, request_id.as_deref(), result, None);
+            return;
+        }
+        Command::Subscribe { topics } => {
+            eprintln!("[system-agent] Client {} subscribing to {:?}", client, topics);
+            clients.subscribe(client, &topics);
+            send_result(clients, client, request_id.as_deref(), Ok(()), None);
+            return;
+        }
+        Command::Unsubscribe { topics } => {
+            eprintln!("[system-agent] Client {} unsubscribing from {:?}", client, topics);
+            clients.unsubscribe(client, &topics);
+            send_result(clients, client, request_id.as_deref(), Ok(()), None);
+            return;
+        }
+        Command::NormalizeShortcut { shortcut } => {
+            // Nothing is grabbed, so the hotkey manager is not involved
+            let (result, data) = match hotkey::parse(&shortcut) {
+                Ok(steps) => (Ok(()), ResultData::Normalized { normalized: hotkey::canonical(&steps) }),
+                Err(e) => (
+                    Err(e.to_string()),
+                    ResultData::ShortcutError { token: e.token, position: e.position, suggestions: e.suggestions },
+                ),
+            };
+            send_result(clients, client, request_id.as_deref(), result, Some(data));
+            return;
+        }
+        Command::Shutdown => {
+            eprintln!("[system-agent] Received shutdown command from client {}", client);
+            send_result(clients, client, request_id.as_deref(), Ok(()), None);
+            let _ = shutdown_sender.send(ShutdownReason::Command);
+            return;
+        }
+        Command::Register(binding) => {
+            eprintln!("[system-agent] Received register command: {} -> {}", binding.id, binding.shortcut);
+            ("register", HotkeyManagerCommand::Register(binding))
         }
-        Command::Unregister(RegisterCommand { id, shortcut }) => {
+        Command::Unregister(RegisterCommand { id, shortcut, .. }) => {
             eprintln!("[system-agent] Received unregister command: {} -> {}", id, shortcut);
-            
-            if let Err(e) = hotkey_sender.send(HotkeyManagerCommand::Unregister { 
-                id: id.clone(), 
-                shortcut: shortcut.clone() 
-            }) {
-                let msg = format!("Failed to send unregister command to hotkey manager: {}", e);
-                eprintln!("[system-agent] {}", msg);
-                send_event(&ErrorEvent { 
-                    event: "error", 
-                    message: msg, 
-                    context: "command_send" 
-                });
-            }
+            ("unregister", HotkeyManagerCommand::Unregister { id, shortcut })
         }
-        Command::RegisterBatch { hotkeys } => {
+        Command::RegisterBatch { hotkeys, atomic } => {
             eprintln!("[system-agent] Received register_batch command with {} hotkeys", hotkeys.len());
-            
-            let batch_hotkeys: Vec<(String, String)> = hotkeys.into_iter()
-                .map(|cmd| (cmd.id, cmd.shortcut))
-                .collect();
-            
-            if let Err(e) = hotkey_sender.send(HotkeyManagerCommand::RegisterBatch { hotkeys: batch_hotkeys }) {
-                let msg = format!("Failed to send register_batch command to hotkey manager: {}", e);
-                eprintln!("[system-agent] {}", msg);
-                send_event(&ErrorEvent { 
-                    event: "error", 
-                    message: msg, 
-                    context: "command_send" 
-                });
-            }
+            ("register_batch", HotkeyManagerCommand::RegisterBatch { hotkeys, atomic })
+        }
+        Command::SyncHotkeys { hotkeys } => {
+            eprintln!("[system-agent] Received sync_hotkeys command with {} hotkeys", hotkeys.len());
+            ("sync_hotkeys", HotkeyManagerCommand::SyncHotkeys { hotkeys })
         }
         Command::UnregisterAll => {
             eprintln!("[system-agent] Received unregister_all command");
-            
-            if let Err(e) = hotkey_sender.send(HotkeyManagerCommand::UnregisterAll) {
-                let msg = format!("Failed to send unregister_all command to hotkey manager: {}", e);
-                eprintln!("[system-agent] {}", msg);
-                send_event(&ErrorEvent { 
-                    event: "error", 
-                    message: msg, 
-                    context: "command_send" 
-                });
-            }
+            ("unregister_all", HotkeyManagerCommand::UnregisterAll)
+        }
+        Command::DefineLayer { name, hotkeys } => {
+            eprintln!("[system-agent] Received define_layer command: {} with {} hotkeys", name, hotkeys.len());
+            ("define_layer", HotkeyManagerCommand::DefineLayer { name, hotkeys })
+        }
+        Command::PushLayer { name } => {
+            eprintln!("[system-agent] Received push_layer command: {}", name);
+            ("push_layer", HotkeyManagerCommand::PushLayer { name })
+        }
+        Command::PopLayer { name } => {
+            eprintln!("[system-agent] Received pop_layer command");
+            ("pop_layer", HotkeyManagerCommand::PopLayer { name })
+        }
+        Command::BeginCapture { timeout_ms } => {
+            eprintln!("[system-agent] Received begin_capture command");
+            ("begin_capture", HotkeyManagerCommand::BeginCapture { timeout_ms })
+        }
+        Command::EndCapture => {
+            eprintln!("[system-agent] Received end_capture command");
+            ("end_capture", HotkeyManagerCommand::EndCapture)
+        }
+        Command::SetContext { values } => {
+            eprintln!("[system-agent] Received set_context command with {} keys", values.len());
+            ("set_context", HotkeyManagerCommand::SetContext { values })
+        }
+        Command::PauseAll { except } => {
+            eprintln!("[system-agent] Received pause_all command");
+            ("pause_all", HotkeyManagerCommand::PauseAll { except })
+        }
+        Command::ResumeAll => {
+            eprintln!("[system-agent] Received resume_all command");
+            ("resume_all", HotkeyManagerCommand::ResumeAll)
+        }
+        Command::Disable { id } => {
+            eprintln!("[system-agent] Received disable command: {}", id);
+            ("disable", HotkeyManagerCommand::SetEnabled { id, enabled: false })
         }
+        Command::Enable { id } => {
+            eprintln!("[system-agent] Received enable command: {}", id);
+            ("enable", HotkeyManagerCommand::SetEnabled { id, enabled: true })
+        }
+        Command::ListHotkeys => {
+            eprintln!("[system-agent] Received list_hotkeys command");
+            ("list_hotkeys", HotkeyManagerCommand::ListHotkeys)
+        }
+        Command::Status => {
+            eprintln!("[system-agent] Received status command");
+            ("status", HotkeyManagerCommand::Status)
+        }
+    };
+
+    // The hotkey manager replies with the result once the change is applied;
+    // only a failure to hand the command over is reported from here.
+    let request = ManagerRequest { client, request_id: request_id.clone(), command };
+    if let Err(e) = hotkey_sender.send(request) {
+        let msg = format!("Failed to send {} command to hotkey manager: {}", name, e);
+        eprintln!("[system-agent] {}", msg);
+        clients.send(client, &Event::Error { 
+            message: msg.clone(), 
+            context: "command_send" 
+        });
+        send_result(clients, client, request_id.as_deref(), Err(msg), None);
+    }
+}
+
+pub(crate) fn send_ready(clients: &Clients, client: ClientId) {
+    clients.send(client, &Event::Ready {
+        protocol_version: PROTOCOL_VERSION,
+        agent_version: env!("CARGO_PKG_VERSION"),
+        backend: display_backend(),
+        commands: COMMANDS,
+        events: EVENTS,
+    });
+}
+
+/// Best-effort guess of the display server the agent is running against.
+#[cfg(target_os = "linux")]
+pub(crate) fn display_backend() -> &'static str {
+    let has_var = |name: &str| std::env::var_os(name).is_some_and(|value| !value.is_empty());
+    if has_var("WAYLAND_DISPLAY") {
+        "wayland"
+    } else if has_var("DISPLAY") {
+        "x11"
+    } else {
+        "none"
     }
 }
 
-fn parse_hotkey(shortcut: &str) -> Result<HotKey, String> {
-    // Parse shortcuts like "Control+Alt+T" or "Shift+F1"
-    // The global-hotkey crate can parse from string directly
-    shortcut.parse::<HotKey>()
-        .map_err(|e| format!("Parse error: {}", e))
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn display_backend() -> &'static str {
+    std::env::consts::OS
 }
 
-fn send_event<T: Serialize>(event: &T) {
-    if let Ok(json) = serde_json::to_string(event) {
-        println!("{}", json);
-        stdout().flush().unwrap_or_default();
+/// Replies to a command that carried a `request_id`. Commands sent without one
+/// keep the original fire-and-forget behaviour, unless there is data to report
+/// (query results, sync reports), which is always sent back.
+fn send_result(
+    clients: &Clients,
+    client: ClientId,
+    request_id: Option<&str>,
+    result: Result<(), String>,
+    data: Option<ResultData>,
+) {
+    if request_id.is_none() && data.is_none() {
+        return;
     }
+    clients.send(client, &Event::Result {
+        request_id: request_id.map(str::to_string),
+        ok: result.is_ok(),
+        error: result.err(),
+        data,
+    });
 }
 
-fn send_raw_event(event_type: &'static str, key: Key) {
-    let key_str = format!("{:?}", key);
-    send_event(&RawKeyEvent { event_type, key: key_str });
-} 
\ No newline at end of file
+fn send_raw_event(clients: &Clients, pressed: bool, key: Key) {
+    let key = format!("{:?}", key);
+    if pressed {
+        clients.broadcast(&Event::KeyPress { key });
+    } else {
+        clients.broadcast(&Event::KeyRelease { key });
+    }
+}
@@ -0,0 +1,124 @@
+use crate::client::ClientId;
+use std::time::{Duration, Instant};
+
+/// How long a capture lasts without a key press, unless the client asks for
+/// another timeout.
+pub const DEFAULT_CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The whole keyboard, grabbed on behalf of one client so that keys reach it
+/// instead of the focused application. The keys themselves are still read
+/// from the raw key stream. A capture ends on Esc, or after `timeout` without
+/// a key press, so that a client that forgets about it cannot lock the user out.
+pub struct Capture {
+    pub client: ClientId,
+    since: Instant,
+    timeout: Duration,
+    deadline: Instant,
+    grab: KeyboardGrab,
+}
+
+impl Capture {
+    pub fn begin(client: ClientId, timeout: Duration, now: Instant) -> Result<Self, String> {
+        let grab = KeyboardGrab::acquire()?;
+        Ok(Self { client, since: now, timeout, deadline: now + timeout, grab })
+    }
+
+    /// Starts the timeout over, with a new length.
+    pub fn renew(&mut self, timeout: Duration, now: Instant) {
+        self.timeout = timeout;
+        self.deadline = now + timeout;
+    }
+
+    /// A key was captured, which keeps the capture alive.
+    pub fn key_pressed(&mut self, now: Instant) {
+        self.deadline = now + self.timeout;
+        self.grab.drain();
+    }
+
+    /// When the capture started; key events from before then are not its own.
+    pub fn since(&self) -> Instant {
+        self.since
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+#[cfg(target_os = "linux")]
+use x11::KeyboardGrab;
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use std::thread;
+    use std::time::Duration;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{ConnectionExt, GrabMode, GrabStatus};
+    use x11rb::rust_connection::RustConnection;
+    use x11rb::CURRENT_TIME;
+
+    /// A hotkey that opens the capture is usually still held, and its passive
+    /// grab keeps the keyboard until it is released.
+    const GRAB_ATTEMPTS: u32 = 10;
+    const GRAB_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+    /// An active X11 keyboard grab, held on a connection of its own and
+    /// released when dropped.
+    pub struct KeyboardGrab {
+        connection: RustConnection,
+    }
+
+    impl KeyboardGrab {
+        pub fn acquire() -> Result<Self, String> {
+            if crate::agent::display_backend() == "wayland" {
+                return Err("Keyboard capture is not supported on Wayland".to_string());
+            }
+            let (connection, screen) = x11rb::connect(None).map_err(|e| format!("Failed to connect to the X server: {}", e))?;
+            let root = connection.setup().roots[screen].root;
+
+            let mut attempts = 1;
+            loop {
+                let status = connection
+                    .grab_keyboard(false, root, CURRENT_TIME, GrabMode::ASYNC, GrabMode::ASYNC)
+                    .map_err(|e| format!("Failed to grab the keyboard: {}", e))?
+                    .reply()
+                    .map_err(|e| format!("Failed to grab the keyboard: {}", e))?
+                    .status;
+                if status == GrabStatus::SUCCESS {
+                    return Ok(Self { connection });
+                }
+                if status != GrabStatus::ALREADY_GRABBED || attempts == GRAB_ATTEMPTS {
+                    return Err(format!("Failed to grab the keyboard: {:?}", status));
+                }
+                attempts += 1;
+                thread::sleep(GRAB_RETRY_INTERVAL);
+            }
+        }
+
+        /// Discards the key events the grab delivers to this connection, so
+        /// they do not pile up unread.
+        pub fn drain(&self) {
+            while let Ok(Some(_)) = self.connection.poll_for_event() {}
+        }
+    }
+
+    impl Drop for KeyboardGrab {
+        fn drop(&mut self) {
+            if let Err(e) = self.connection.ungrab_keyboard(CURRENT_TIME).map(|_| ()).and_then(|()| self.connection.flush()) {
+                eprintln!("[system-agent] Failed to release the keyboard grab: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct KeyboardGrab;
+
+#[cfg(not(target_os = "linux"))]
+impl KeyboardGrab {
+    fn acquire() -> Result<Self, String> {
+        Err("Keyboard capture is only supported on X11".to_string())
+    }
+
+    fn drain(&self) {}
+}
@@ -0,0 +1,301 @@
+use crate::cmd::Topic;
+use crate::event::{self, Event};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Identifies one connected client. In stdio mode the parent process is the
+/// only client; in socket mode every connection gets its own id.
+pub type ClientId = u64;
+
+/// Events buffered per client before its writer thread falls behind.
+const QUEUE_CAPACITY: usize = 1024;
+/// Raw key events are only queued below this fill level, so that events which
+/// must never be dropped always find room in the rest of the queue.
+const DROPPABLE_HIGH_WATER: usize = QUEUE_CAPACITY * 3 / 4;
+/// How often a writer thread reports events dropped since its last report.
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The producer side of a client's output queue. Cheap to clone, so events
+/// can be queued without holding the client map lock.
+#[derive(Clone)]
+struct Outlet {
+    client: ClientId,
+    queue: Sender<Event>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Outlet {
+    /// Queues an event without ever waiting, returning `false` once the
+    /// client should be disconnected: its writer thread has gone away, or it
+    /// is so far behind that even the room kept for events that must not be
+    /// dropped is used up. Raw key events are dropped (and counted) when the
+    /// queue is filling up.
+    fn push(&self, event: &Event) -> bool {
+        if !event.is_droppable() {
+            return match self.queue.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    eprintln!("[system-agent] Client {} stopped reading its events, disconnecting it", self.client);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            };
+        }
+        if self.queue.len() >= DROPPABLE_HIGH_WATER {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        match self.queue.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Closes a client's connection, so that its command listener sees it end
+/// and its hotkeys are released. The stdio client cannot be closed on its
+/// own, so its hangup shuts the agent down instead.
+pub type Hangup = Box<dyn FnOnce() + Send>;
+
+struct Client {
+    outlet: Outlet,
+    writer_thread: JoinHandle<()>,
+    subscriptions: HashSet<Topic>,
+    hangup: Hangup,
+}
+
+impl Client {
+    fn wants(&self, event: &Event) -> bool {
+        event.topic().is_none_or(|topic| self.subscriptions.contains(&topic))
+    }
+}
+
+/// All connected clients and their output streams. Every event goes through
+/// here so that each client only sees its own results and hotkeys, and only
+/// the topics it subscribed to.
+///
+/// Each client has a dedicated writer thread behind a bounded queue, so a slow
+/// reader never stalls the thread that produced an event (in particular the
+/// global input hook or the hotkey thread). A reader too slow to keep up at
+/// all is disconnected.
+#[derive(Default)]
+pub struct Clients {
+    clients: Mutex<HashMap<ClientId, Client>>,
+    next_id: AtomicU64,
+}
+
+impl Clients {
+    pub fn connect(&self, writer: Box<dyn Write + Send>, hangup: Hangup) -> ClientId {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (queue, receiver) = bounded(QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let dropped_clone = dropped.clone();
+        let writer_thread = thread::Builder::new()
+            .name(format!("client-{}-writer", id))
+            .spawn(move || writer_loop(id, writer, receiver, dropped_clone))
+            .expect("Failed to spawn client writer thread");
+
+        let client = Client {
+            outlet: Outlet { client: id, queue, dropped },
+            writer_thread,
+            subscriptions: Topic::DEFAULT.iter().copied().collect(),
+            hangup,
+        };
+        self.clients.lock().unwrap().insert(id, client);
+        eprintln!("[system-agent] Client {} connected", id);
+        id
+    }
+
+    pub fn disconnect(&self, client: ClientId) {
+        let removed = self.clients.lock().unwrap().remove(&client);
+        if let Some(removed) = removed {
+            eprintln!("[system-agent] Client {} disconnected", client);
+            (removed.hangup)();
+        }
+    }
+
+    /// Disconnects every client and waits for their writer threads to flush
+    /// whatever is still queued.
+    pub fn shutdown(&self) {
+        let clients: Vec<Client> = self.clients.lock().unwrap().drain().map(|(_, client)| client).collect();
+        for client in clients {
+            // Dropping the outlet closes the queue; the writer drains it and exits
+            drop(client.outlet);
+            if client.writer_thread.join().is_err() {
+                eprintln!("[system-agent] Client writer thread panicked");
+            }
+        }
+    }
+
+    pub fn subscribe(&self, client: ClientId, topics: &[Topic]) {
+        if let Some(output) = self.clients.lock().unwrap().get_mut(&client) {
+            output.subscriptions.extend(topics.iter().copied());
+        }
+    }
+
+    pub fn unsubscribe(&self, client: ClientId, topics: &[Topic]) {
+        if let Some(output) = self.clients.lock().unwrap().get_mut(&client) {
+            output.subscriptions.retain(|topic| !topics.contains(topic));
+        }
+    }
+
+    /// Whether any client would receive events of this topic. Lets producers
+    /// skip building events nobody is listening to.
+    pub fn wants(&self, topic: Topic) -> bool {
+        self.clients.lock().unwrap().values().any(|output| output.subscriptions.contains(&topic))
+    }
+
+    /// Number of connected clients.
+    pub fn count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// How many clients are subscribed to each topic.
+    pub fn subscriber_counts(&self) -> BTreeMap<Topic, usize> {
+        let mut counts = BTreeMap::new();
+        for output in self.clients.lock().unwrap().values() {
+            for topic in &output.subscriptions {
+                *counts.entry(*topic).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Events queued for all clients but not written yet.
+    pub fn queued_events(&self) -> usize {
+        self.clients.lock().unwrap().values().map(|output| output.outlet.queue.len()).sum()
+    }
+
+    /// Queues an event for a single client if it is subscribed to the event's
+    /// topic, dropping the client if its writer has gone away.
+    pub fn send(&self, client: ClientId, event: &Event) {
+        let outlet = {
+            let clients = self.clients.lock().unwrap();
+            clients.get(&client).filter(|output| output.wants(event)).map(|output| output.outlet.clone())
+        };
+        if let Some(outlet) = outlet {
+            if !outlet.push(event) {
+                self.disconnect(client);
+            }
+        }
+    }
+
+    /// Queues an event for every client subscribed to its topic.
+    pub fn broadcast(&self, event: &Event) {
+        let outlets: Vec<(ClientId, Outlet)> = {
+            let clients = self.clients.lock().unwrap();
+            clients
+                .iter()
+                .filter(|(_, output)| output.wants(event))
+                .map(|(client, output)| (*client, output.outlet.clone()))
+                .collect()
+        };
+        for (client, outlet) in outlets {
+            if !outlet.push(event) {
+                self.disconnect(client);
+            }
+        }
+    }
+}
+
+/// Serializes and writes one client's events in order, numbering them and
+/// periodically reporting how many raw key events had to be dropped.
+fn writer_loop(client: ClientId, mut writer: Box<dyn Write + Send>, queue: Receiver<Event>, dropped: Arc<AtomicU64>) {
+    let mut next_seq = 0;
+    let mut write = |event: &Event| -> io::Result<()> {
+        let json = event::encode(event, next_seq)?;
+        next_seq += 1;
+        writeln!(writer, "{}", json)?;
+        writer.flush()
+    };
+
+    let mut last_report = Instant::now();
+    loop {
+        let result = match queue.recv_timeout(DROP_REPORT_INTERVAL) {
+            Ok(event) => write(&event),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let result = result.and_then(|()| {
+            if last_report.elapsed() < DROP_REPORT_INTERVAL {
+                return Ok(());
+            }
+            last_report = Instant::now();
+            match dropped.swap(0, Ordering::Relaxed) {
+                0 => Ok(()),
+                count => write(&Event::Dropped { count }),
+            }
+        });
+
+        if let Err(e) = result {
+            eprintln!("[system-agent] Failed to write to client {}: {}", client, e);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::ShutdownReason;
+
+    #[test]
+    fn full_queue_disconnects_instead_of_waiting() {
+        let (queue, receiver) = bounded(2);
+        let outlet = Outlet { client: 0, queue, dropped: Arc::new(AtomicU64::new(0)) };
+        let pressed = Event::HotkeyPressed { id: "quit".to_string() };
+
+        assert!(outlet.push(&pressed));
+        assert!(outlet.push(&pressed));
+        assert!(!outlet.push(&pressed));
+        assert_eq!(receiver.len(), 2);
+
+        drop(receiver);
+        assert!(!outlet.push(&pressed));
+    }
+
+    /// Never finishes a write until the test lets go of it.
+    struct StuckWriter(Receiver<()>);
+
+    impl Write for StuckWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stdio_client_that_stops_reading_shuts_the_agent_down() {
+        let (release, stuck) = bounded(0);
+        let (shutdown_sender, shutdown_receiver) = crossbeam_channel::unbounded();
+        let clients = Clients::default();
+        let client = clients.connect(
+            Box::new(StuckWriter(stuck)),
+            Box::new(move || {
+                let _ = shutdown_sender.send(ShutdownReason::Error);
+            }),
+        );
+
+        for _ in 0..=QUEUE_CAPACITY + 1 {
+            clients.send(client, &Event::HotkeyPressed { id: "quit".to_string() });
+        }
+        assert_eq!(shutdown_receiver.try_recv(), Ok(ShutdownReason::Error));
+        assert_eq!(clients.count(), 0);
+        drop(release);
+    }
+}
@@ -1,20 +1,304 @@
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
 
-#[derive(Deserialize, Debug, Clone)]
+/// Version of the stdin/stdout protocol. Bumped on breaking changes only;
+/// additions are advertised through the command and event lists instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Commands understood by this build, advertised in the `ready` event.
+pub const COMMANDS: &[&str] = &[
+    "hello",
+    "register",
+    "unregister",
+    "register_batch",
+    "sync_hotkeys",
+    "unregister_all",
+    "define_layer",
+    "push_layer",
+    "pop_layer",
+    "begin_capture",
+    "end_capture",
+    "set_context",
+    "pause_all",
+    "resume_all",
+    "disable",
+    "enable",
+    "subscribe",
+    "unsubscribe",
+    "list_hotkeys",
+    "normalize_shortcut",
+    "status",
+    "shutdown",
+];
+
+/// A single line read from stdin. Every command may carry an optional
+/// `request_id`, which is echoed back in the matching `result` event once the
+/// command has been applied.
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+pub struct Request {
+    /// Opaque correlation id, echoed back in the `result` event.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "command")]
 pub enum Command {
+    /// Ask the agent to describe itself; answered with a `ready` event.
+    #[serde(rename = "hello")]
+    Hello {
+        /// Protocol version the client was written against.
+        #[serde(default)]
+        protocol_version: Option<u32>,
+    },
+    /// Grab a global shortcut and report it as `hotkey_pressed` with the given id.
     #[serde(rename = "register")]
     Register(RegisterCommand),
+    /// Release a hotkey previously registered under the given id.
     #[serde(rename = "unregister")]
     Unregister(RegisterCommand),
+    /// Register several hotkeys with a single command.
     #[serde(rename = "register_batch")]
-    RegisterBatch { hotkeys: Vec<RegisterCommand> },
+    RegisterBatch {
+        hotkeys: Vec<RegisterCommand>,
+        /// Register all of the hotkeys or none of them, leaving the previous
+        /// registrations in place if any of them fails.
+        #[serde(default)]
+        atomic: bool,
+    },
+    /// Make the client's hotkeys exactly this set, releasing and grabbing only
+    /// what changed. Answered with a per-hotkey report, even without `request_id`.
+    #[serde(rename = "sync_hotkeys")]
+    SyncHotkeys { hotkeys: Vec<RegisterCommand> },
+    /// Release every registered hotkey.
     #[serde(rename = "unregister_all")]
     UnregisterAll,
+    /// Define a named layer of hotkeys, or replace its definition. Nothing is
+    /// grabbed until the layer is pushed.
+    #[serde(rename = "define_layer")]
+    DefineLayer { name: String, hotkeys: Vec<RegisterCommand> },
+    /// Activate a layer on top of the client's hotkeys. Its hotkeys shadow the
+    /// client's hotkeys below it with the same id or the same shortcut, until
+    /// it is popped. Answered with the active layers as `data.layers`.
+    #[serde(rename = "push_layer")]
+    PushLayer { name: String },
+    /// Deactivate the top layer, putting back what it shadowed. Answered with
+    /// the active layers as `data.layers`.
+    #[serde(rename = "pop_layer")]
+    PopLayer {
+        /// Only pop if this is the top layer.
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// Grab the whole keyboard and report every key as `captured_key` instead
+    /// of letting it reach the focused application, until `end_capture`, Esc,
+    /// or `timeout_ms` without a key press. X11 only.
+    #[serde(rename = "begin_capture")]
+    BeginCapture {
+        /// Defaults to 10000.
+        #[serde(default)]
+        #[schemars(range(min = 1))]
+        timeout_ms: Option<u64>,
+    },
+    /// Release the keyboard grabbed with `begin_capture`.
+    #[serde(rename = "end_capture")]
+    EndCapture,
+    /// Set keys of the client's context, which its hotkeys' `when` expressions
+    /// read. A `null` value removes the key.
+    #[serde(rename = "set_context")]
+    SetContext { values: BTreeMap<String, Option<ContextValue>> },
+    /// Release every grab, of every client, while keeping the registrations,
+    /// so that nothing fires until `resume_all`.
+    #[serde(rename = "pause_all")]
+    PauseAll {
+        /// One of the client's hotkeys that stays active, to resume with.
+        #[serde(default)]
+        except: Option<String>,
+    },
+    /// Grab again what `pause_all` released. Only the client that paused
+    /// may resume.
+    #[serde(rename = "resume_all")]
+    ResumeAll,
+    /// Release one hotkey's grab while keeping its registration.
+    #[serde(rename = "disable")]
+    Disable { id: String },
+    /// Grab a disabled hotkey again.
+    #[serde(rename = "enable")]
+    Enable { id: String },
+    /// Start receiving events of the given topics.
+    #[serde(rename = "subscribe")]
+    Subscribe { topics: Vec<Topic> },
+    /// Stop receiving events of the given topics.
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe { topics: Vec<Topic> },
+    /// Describe the hotkeys registered by this client; answered with a `result`
+    /// carrying `data.hotkeys`.
+    #[serde(rename = "list_hotkeys")]
+    ListHotkeys,
+    /// Check a shortcut without registering it. Answered with its canonical
+    /// spelling as `data.normalized`, or, if it is invalid, with the offending
+    /// token, its position and suggestions in `data`.
+    #[serde(rename = "normalize_shortcut")]
+    NormalizeShortcut { shortcut: String },
+    /// Describe the agent itself; answered with a `result` carrying the status as `data`.
+    #[serde(rename = "status")]
+    Status,
+    /// Release every grab and exit, after a `shutting_down` event to all clients.
+    #[serde(rename = "shutdown")]
+    Shutdown,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub struct RegisterCommand {
+    /// Caller-chosen id reported back in `hotkey_pressed` and `hotkey_released`.
     pub id: String,
+    /// Shortcut such as `Control+Alt+Q`, as understood by global-hotkey, or
+    /// several separated by spaces for a sequence such as `Control+Alt+K S`.
+    /// Modifiers alone, such as `RightAlt`, fire when tapped without another key.
+    /// A key may be given by position, as `code:KeyZ`, or by the character it
+    /// types in the active layout, as `char:z`.
     pub shortcut: String,
-} 
\ No newline at end of file
+    /// What to do when another hotkey already uses this shortcut.
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// Which transitions to report. Defaults to `["pressed"]`; add `released`
+    /// to also get `hotkey_released` with the hold duration.
+    #[serde(default = "HotkeyEventKind::default_set")]
+    pub events: Vec<HotkeyEventKind>,
+    /// When the hotkey counts as pressed: `press` (as soon as it goes down, the
+    /// default), `tap` (released quickly) or `hold:<ms>` (held for that long).
+    #[serde(default)]
+    #[schemars(with = "String", regex(pattern = r"^(press|tap|hold:[0-9]+)$"))]
+    pub trigger: Trigger,
+    /// Fire on this many quick taps of the shortcut instead, e.g. 2 for a
+    /// double tap. The shortcut may then be modifiers alone, like `Control`.
+    #[serde(default)]
+    #[schemars(range(min = 2))]
+    pub taps: Option<u32>,
+    /// With `taps`: the longest tap and the longest pause between taps.
+    /// Defaults to 300.
+    #[serde(default)]
+    #[schemars(range(min = 1))]
+    pub within_ms: Option<u64>,
+    /// Only fire, and only hold the shortcut, while the focused window
+    /// matches, or while an expression over the client's context holds, such
+    /// as `mode == 'spellbook' && !spellRunning`. Otherwise the keys reach the
+    /// application as if unregistered.
+    #[serde(default)]
+    pub when: Option<When>,
+}
+
+/// See `RegisterCommand::when`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum When {
+    Window(WindowScope),
+    /// Context keys set with `set_context` and `window.class`,
+    /// `window.instance`, `window.process` or `window.title`, compared with
+    /// `==`, `!=`, `<`, `<=`, `>`, `>=` or `=~` (a regular expression) to
+    /// strings, numbers or booleans, and combined with `!`, `&&`, `||` and
+    /// parentheses. A key on its own is true if set to `true`, a non-zero
+    /// number or a non-empty string.
+    Expression(String),
+}
+
+/// A value of a context key.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ContextValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+/// Windows a hotkey is limited to; all given conditions must hold. Only
+/// known on X11, where the agent follows `_NET_ACTIVE_WINDOW`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct WindowScope {
+    /// The instance or class part of `WM_CLASS`, ignoring case.
+    #[serde(default)]
+    pub class: Option<String>,
+    /// Name of the window's process, as in `/proc/<pid>/comm`, ignoring case.
+    #[serde(default)]
+    pub process: Option<String>,
+    /// Regular expression searched for in the title; `(?i)` ignores case.
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// See `RegisterCommand::trigger`.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Trigger {
+    #[default]
+    Press,
+    Tap,
+    Hold(Duration),
+}
+
+impl TryFrom<String> for Trigger {
+    type Error = String;
+
+    fn try_from(trigger: String) -> Result<Self, Self::Error> {
+        match trigger.as_str() {
+            "press" => Ok(Trigger::Press),
+            "tap" => Ok(Trigger::Tap),
+            other => match other.strip_prefix("hold:").map(str::parse::<u64>) {
+                Some(Ok(ms)) if ms > 0 => Ok(Trigger::Hold(Duration::from_millis(ms))),
+                _ => Err(format!("invalid trigger {:?}, expected press, tap or hold:<ms>", trigger)),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HotkeyEventKind {
+    /// Report `hotkey_pressed` when the combination goes down.
+    Pressed,
+    /// Report `hotkey_released` when it comes back up.
+    Released,
+}
+
+impl HotkeyEventKind {
+    fn default_set() -> Vec<HotkeyEventKind> {
+        vec![HotkeyEventKind::Pressed]
+    }
+}
+
+/// How a registration treats another hotkey, of any client, on the same shortcut.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Fail with a `conflict` error naming the hotkey that holds the shortcut.
+    #[default]
+    Reject,
+    /// Deliver the press to every hotkey on the shortcut, provided they all
+    /// allow sharing.
+    Share,
+}
+
+/// Groups of events a client can subscribe to. `ready` and `result` events
+/// are not part of any topic and are always delivered.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// `hotkey_*` and `sequence_*` events for the client's own hotkeys, and
+    /// `captured_key`/`capture_ended` for its keyboard capture. On by default.
+    Hotkeys,
+    /// Every `key_press`/`key_release` on the system. Off by default.
+    RawKeys,
+    /// `error` events. On by default.
+    Errors,
+    /// Reserved for clipboard change events; the agent does not emit any yet.
+    Clipboard,
+}
+
+impl Topic {
+    /// Topics a client is subscribed to when it connects.
+    pub const DEFAULT: &'static [Topic] = &[Topic::Hotkeys, Topic::Errors];
+}
@@ -0,0 +1,402 @@
+use crate::cmd::{ContextValue, When};
+use crate::window::{FocusedWindow, Pattern, Scope};
+use std::collections::BTreeMap;
+
+/// The keys a client has set with `set_context`.
+pub type Context = BTreeMap<String, ContextValue>;
+
+/// A `when` clause, checked and ready to evaluate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Window(Scope),
+    Expression(Expression),
+}
+
+impl Condition {
+    pub fn new(when: &When) -> Result<Self, String> {
+        match when {
+            When::Window(scope) => Scope::new(scope).map(Condition::Window),
+            When::Expression(source) => Expression::parse(source).map(Condition::Expression),
+        }
+    }
+
+    pub fn holds(&self, window: Option<&FocusedWindow>, context: &Context) -> bool {
+        match self {
+            Condition::Window(scope) => scope.matches(window),
+            Condition::Expression(expression) => expression.holds(window, context),
+        }
+    }
+}
+
+/// A boolean expression over context keys, in the spirit of VS Code's `when`
+/// clauses: `mode == 'spellbook' && !spellRunning`. Keys under `window.`
+/// describe the focused window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression(Node);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Key(String),
+    Literal(Value),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(Box<Node>, Operator, Box<Node>),
+    Matches(Box<Node>, Pattern),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// What a key or literal evaluates to. Keys that were never set are `Unset`.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Unset,
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Unset => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0,
+            Value::Text(text) => !text.is_empty(),
+        }
+    }
+}
+
+impl From<&ContextValue> for Value {
+    fn from(value: &ContextValue) -> Self {
+        match value {
+            ContextValue::Bool(b) => Value::Bool(*b),
+            ContextValue::Number(n) => Value::Number(*n),
+            ContextValue::String(text) => Value::Text(text.clone()),
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, at: 0, end: source.chars().count() };
+        let node = parser.or()?;
+        match parser.tokens.get(parser.at) {
+            None => Ok(Self(node)),
+            Some((_, position)) => Err(format!("Unexpected {} at position {}", parser.describe(parser.at), position)),
+        }
+    }
+
+    pub fn holds(&self, window: Option<&FocusedWindow>, context: &Context) -> bool {
+        evaluate(&self.0, window, context).truthy()
+    }
+}
+
+fn evaluate(node: &Node, window: Option<&FocusedWindow>, context: &Context) -> Value {
+    let value = |node: &Node| evaluate(node, window, context);
+    match node {
+        Node::Key(key) => lookup(key, window, context),
+        Node::Literal(literal) => literal.clone(),
+        Node::Not(operand) => Value::Bool(!value(operand).truthy()),
+        Node::And(left, right) => Value::Bool(value(left).truthy() && value(right).truthy()),
+        Node::Or(left, right) => Value::Bool(value(left).truthy() || value(right).truthy()),
+        Node::Compare(left, operator, right) => {
+            let (left, right) = (value(left), value(right));
+            Value::Bool(match operator {
+                Operator::Equal => left == right,
+                Operator::NotEqual => left != right,
+                // Only numbers are ordered
+                ordering => match (left, right) {
+                    (Value::Number(left), Value::Number(right)) => match ordering {
+                        Operator::Less => left < right,
+                        Operator::LessOrEqual => left <= right,
+                        Operator::Greater => left > right,
+                        _ => left >= right,
+                    },
+                    _ => false,
+                },
+            })
+        }
+        Node::Matches(operand, pattern) => Value::Bool(matches!(value(operand), Value::Text(text) if pattern.is_match(&text))),
+    }
+}
+
+fn lookup(key: &str, window: Option<&FocusedWindow>, context: &Context) -> Value {
+    let text = |text: &str| Value::Text(text.to_string());
+    match (key.strip_prefix("window."), window) {
+        (Some("class"), Some(window)) => text(&window.class),
+        (Some("instance"), Some(window)) => text(&window.instance),
+        (Some("title"), Some(window)) => text(&window.title),
+        (Some("process"), Some(window)) => window.process.as_deref().map_or(Value::Unset, text),
+        (Some(_), _) => Value::Unset,
+        (None, _) => context.get(key).map_or(Value::Unset, Value::from),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Key(String),
+    Literal(Value),
+    Not,
+    And,
+    Or,
+    Compare(Operator),
+    Matches,
+    Open,
+    Close,
+}
+
+/// Splits an expression into tokens, each with the position it starts at.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut at = 0;
+    while let Some(&c) = chars.get(at) {
+        let start = at;
+        let next = chars.get(at + 1).copied();
+        let token = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                at += 1;
+                continue;
+            }
+            ('&', Some('&')) => Token::And,
+            ('|', Some('|')) => Token::Or,
+            ('=', Some('=')) => Token::Compare(Operator::Equal),
+            ('!', Some('=')) => Token::Compare(Operator::NotEqual),
+            ('<', Some('=')) => Token::Compare(Operator::LessOrEqual),
+            ('>', Some('=')) => Token::Compare(Operator::GreaterOrEqual),
+            ('=', Some('~')) => Token::Matches,
+            ('!', _) => Token::Not,
+            ('<', _) => Token::Compare(Operator::Less),
+            ('>', _) => Token::Compare(Operator::Greater),
+            ('(', _) => Token::Open,
+            (')', _) => Token::Close,
+            ('\'' | '"', _) => {
+                let mut text = String::new();
+                at += 1;
+                loop {
+                    match chars.get(at) {
+                        None => return Err(format!("Unterminated string at position {}", start)),
+                        Some(&quote) if quote == c => break,
+                        Some('\\') if chars.get(at + 1).is_some() => {
+                            text.push(chars[at + 1]);
+                            at += 2;
+                        }
+                        Some(&other) => {
+                            text.push(other);
+                            at += 1;
+                        }
+                    }
+                }
+                at += 1;
+                tokens.push((Token::Literal(Value::Text(text)), start));
+                continue;
+            }
+            (c, next) if c.is_ascii_digit() || c == '-' && next.is_some_and(|next| next.is_ascii_digit()) => {
+                at += 1;
+                while chars.get(at).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                    at += 1;
+                }
+                let number: String = chars[start..at].iter().collect();
+                let number = number.parse().map_err(|_| format!("Invalid number {} at position {}", number, start))?;
+                tokens.push((Token::Literal(Value::Number(number)), start));
+                continue;
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                while chars.get(at).is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '.')) {
+                    at += 1;
+                }
+                let word: String = chars[start..at].iter().collect();
+                let token = match word.as_str() {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    _ => Token::Key(word),
+                };
+                tokens.push((token, start));
+                continue;
+            }
+            (c, _) => return Err(format!("Unexpected {:?} at position {}", c, start)),
+        };
+        at += match token {
+            Token::Not | Token::Compare(Operator::Less | Operator::Greater) | Token::Open | Token::Close => 1,
+            _ => 2,
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent, loosest first: `||`, `&&`, comparisons, `!`.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    at: usize,
+    /// Where the expression ends, for errors there.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at).map(|(token, _)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.at).map_or(self.end, |(_, position)| *position)
+    }
+
+    fn describe(&self, at: usize) -> String {
+        match self.tokens.get(at).map(|(token, _)| token) {
+            None => "end of expression".to_string(),
+            Some(Token::Key(key)) => format!("key {}", key),
+            Some(Token::Literal(_)) => "value".to_string(),
+            Some(Token::Not) => "!".to_string(),
+            Some(Token::And) => "&&".to_string(),
+            Some(Token::Or) => "||".to_string(),
+            Some(Token::Compare(_) | Token::Matches) => "operator".to_string(),
+            Some(Token::Open) => "(".to_string(),
+            Some(Token::Close) => ")".to_string(),
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.at += 1;
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.comparison()?;
+        while self.peek() == Some(&Token::And) {
+            self.at += 1;
+            node = Node::And(Box::new(node), Box::new(self.comparison()?));
+        }
+        Ok(node)
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        let left = self.unary()?;
+        match self.peek() {
+            Some(&Token::Compare(operator)) => {
+                self.at += 1;
+                Ok(Node::Compare(Box::new(left), operator, Box::new(self.unary()?)))
+            }
+            Some(Token::Matches) => {
+                self.at += 1;
+                let position = self.position();
+                let Some(Token::Literal(Value::Text(source))) = self.peek() else {
+                    return Err(format!("Expected a quoted regular expression at position {}", position));
+                };
+                let pattern = Pattern::new(source).map_err(|e| format!("Invalid regular expression at position {}: {}", position, e))?;
+                self.at += 1;
+                Ok(Node::Matches(Box::new(left), pattern))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.at += 1;
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let position = self.position();
+        let node = match self.peek() {
+            Some(Token::Key(key)) => Node::Key(key.clone()),
+            Some(Token::Literal(value)) => Node::Literal(value.clone()),
+            Some(Token::Open) => {
+                self.at += 1;
+                let node = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(format!("Expected ) at position {}", self.position()));
+                }
+                node
+            }
+            _ => return Err(format!("Expected a key or value at position {}, found {}", position, self.describe(self.at))),
+        };
+        self.at += 1;
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(values: &[(&str, ContextValue)]) -> Context {
+        values.iter().map(|(key, value)| (key.to_string(), value.clone())).collect()
+    }
+
+    fn holds(source: &str, context: &Context) -> bool {
+        Expression::parse(source).unwrap().holds(None, context)
+    }
+
+    #[test]
+    fn compares_and_combines_keys() {
+        let spellbook = context(&[
+            ("mode", ContextValue::String("spellbook".to_string())),
+            ("spellRunning", ContextValue::Bool(false)),
+            ("level", ContextValue::Number(3.0)),
+        ]);
+        assert!(holds("mode == 'spellbook' && !spellRunning", &spellbook));
+        assert!(!holds("mode == \"editor\" || spellRunning", &spellbook));
+        assert!(holds("level >= 3 && level < 10 && level != 4", &spellbook));
+        assert!(holds("!(mode == 'editor' || level > 3)", &spellbook));
+        assert!(holds("mode =~ '^spell'", &spellbook));
+        assert!(!holds("level == '3'", &spellbook));
+    }
+
+    #[test]
+    fn matching_takes_linear_time() {
+        let long = context(&[("text", ContextValue::String("a".repeat(10_000)))]);
+        assert!(!holds("text =~ '(a*)*b'", &long));
+        assert!(holds("text =~ '^(a|aa)+$'", &long));
+    }
+
+    #[test]
+    fn unset_keys_are_false() {
+        let empty = Context::new();
+        assert!(!holds("spellRunning", &empty));
+        assert!(holds("!spellRunning", &empty));
+        assert!(!holds("mode == 'spellbook'", &empty));
+        assert!(!holds("count > -1", &empty));
+    }
+
+    #[test]
+    fn window_keys_describe_the_focused_window() {
+        let window = FocusedWindow {
+            instance: "code".to_string(),
+            class: "Code".to_string(),
+            process: None,
+            title: "notes.ipynb - Visual Studio Code".to_string(),
+        };
+        let expression = Expression::parse("window.class == 'Code' && window.title =~ '(?i)\\.IPYNB' && !window.process").unwrap();
+        assert!(expression.holds(Some(&window), &Context::new()));
+        assert!(!expression.holds(None, &Context::new()));
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        assert_eq!(Expression::parse("mode ==").unwrap_err(), "Expected a key or value at position 7, found end of expression");
+        assert_eq!(Expression::parse("(a && b").unwrap_err(), "Expected ) at position 7");
+        assert_eq!(Expression::parse("a b").unwrap_err(), "Unexpected key b at position 2");
+        assert_eq!(Expression::parse("mode == 'x").unwrap_err(), "Unterminated string at position 8");
+        assert_eq!(Expression::parse("a & b").unwrap_err(), "Unexpected '&' at position 2");
+        assert!(Expression::parse("title =~ '('").unwrap_err().starts_with("Invalid regular expression at position 9: "));
+        assert!(Expression::parse("title =~ other").is_err());
+    }
+}
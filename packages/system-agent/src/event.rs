@@ -0,0 +1,338 @@
+use crate::cmd::Topic;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Version of the event envelope, emitted as `v` on every event.
+pub const EVENT_VERSION: u32 = 1;
+
+/// Event types this build can emit, advertised in the `ready` event.
+pub const EVENTS: &[&str] = &["ready", "result", "hotkey_pressed", "hotkey_released", "sequence_pending", "sequence_cancelled", "captured_key", "capture_ended", "key_press", "key_release", "error", "dropped", "shutting_down"];
+
+/// Everything the agent writes to stdout. Serialized with a `type` tag and
+/// wrapped in an envelope carrying `v`, `seq` and `ts`, e.g.
+/// `{"v":1,"seq":3,"ts":1520,"type":"hotkey_pressed","id":"..."}`.
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Sent on startup and in reply to `hello`.
+    Ready {
+        protocol_version: u32,
+        agent_version: &'static str,
+        backend: &'static str,
+        commands: &'static [&'static str],
+        events: &'static [&'static str],
+    },
+    /// Reply to a command that carried a `request_id`, and to every query.
+    Result {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        /// What a query command asked for.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<ResultData>,
+    },
+    /// A registered hotkey was pressed.
+    HotkeyPressed {
+        id: String,
+    },
+    /// A hotkey registered with `"released"` in its `events` was let go.
+    HotkeyReleased {
+        id: String,
+        /// How long the combination was held, in milliseconds.
+        held_ms: u64,
+    },
+    /// The first steps of sequence hotkeys were typed; the agent captures the
+    /// keys that would continue them until one is pressed or `timeout_ms` passes.
+    SequencePending {
+        /// The steps typed so far, normalized and separated by spaces.
+        prefix: String,
+        /// This client's hotkeys that the next step may complete.
+        ids: Vec<String>,
+        timeout_ms: u64,
+    },
+    /// A pending sequence ended without being completed.
+    SequenceCancelled {
+        prefix: String,
+        ids: Vec<String>,
+        reason: SequenceCancelReason,
+    },
+    /// A key went down while the client had the keyboard captured. Modifiers
+    /// alone are not reported, and Esc ends the capture instead.
+    CapturedKey {
+        /// The key's `KeyboardEvent.code`, such as `KeyA` or `ArrowUp`, or
+        /// the raw key name for keys without one.
+        key: String,
+        /// The text the key types with the current layout and modifiers, if any.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        char: Option<String>,
+        /// The modifiers held, as `control`, `alt`, `shift` and `super`.
+        modifiers: Vec<&'static str>,
+    },
+    /// The client's keyboard capture ended and the keyboard was released.
+    CaptureEnded {
+        reason: CaptureEndReason,
+    },
+    /// Any key went down, as seen by the raw key stream.
+    KeyPress {
+        key: String,
+    },
+    /// Any key went up, as seen by the raw key stream.
+    KeyRelease {
+        key: String,
+    },
+    /// Something went wrong outside of a request, or alongside a failed `result`.
+    Error {
+        message: String,
+        context: &'static str,
+    },
+    /// Raw key events were discarded because the client was not reading fast
+    /// enough. Reported at most once per second with the count since the last report.
+    Dropped {
+        count: u64,
+    },
+    /// The agent has released its grabs and is about to exit. Always the last event.
+    ShuttingDown {
+        reason: ShutdownReason,
+    },
+}
+
+/// Why a pending sequence was given up.
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SequenceCancelReason {
+    /// No further step came in time.
+    Timeout,
+    /// A key that does not continue the sequence was pressed.
+    OtherKey,
+}
+
+/// Why a keyboard capture ended.
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureEndReason {
+    /// The client sent `end_capture`.
+    Command,
+    /// The user pressed Esc.
+    Escape,
+    /// No key was pressed for `timeout_ms`.
+    Timeout,
+}
+
+/// What made the agent shut down.
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownReason {
+    /// A client sent the `shutdown` command.
+    Command,
+    /// stdin reached EOF in stdio mode.
+    StdinClosed,
+    /// The process that launched the agent in stdio mode went away.
+    ParentExited,
+    /// SIGINT or SIGTERM.
+    Signal,
+    /// The agent could not start serving, e.g. because the socket is in use,
+    /// or the parent stopped reading its output in stdio mode.
+    Error,
+}
+
+/// Payload of a successful query, carried as `data` in its `result` event.
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum ResultData {
+    /// Reply to `list_hotkeys`.
+    Hotkeys { hotkeys: Vec<HotkeyInfo> },
+    /// Reply to `status`.
+    Status(AgentStatus),
+    /// Reply to `sync_hotkeys`, one entry per hotkey that was kept, touched or rejected.
+    Sync { changes: Vec<SyncChange> },
+    /// Reply to a `register_batch` in which some hotkeys failed.
+    Failures { failures: Vec<HotkeyFailure> },
+    /// Reply to `push_layer` and `pop_layer`: the active layers, bottom first.
+    Layers { layers: Vec<String> },
+    /// Reply to `normalize_shortcut` for a valid shortcut.
+    Normalized { normalized: String },
+    /// Reply to `normalize_shortcut` for an invalid shortcut.
+    ShortcutError {
+        token: String,
+        /// Where `token` starts, in characters.
+        position: usize,
+        /// Known key names close to `token`, best first.
+        suggestions: Vec<String>,
+    },
+}
+
+/// A hotkey of a batch that could not be registered.
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct HotkeyFailure {
+    pub id: String,
+    pub shortcut: String,
+    pub error: String,
+}
+
+/// What `sync_hotkeys` did with one hotkey id.
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct SyncChange {
+    pub id: String,
+    pub action: SyncAction,
+    /// Why the hotkey could not be brought in line; only present for `failed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SyncChange {
+    pub fn failed(id: String, error: String) -> Self {
+        Self { id, action: SyncAction::Failed, error: Some(error) }
+    }
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncAction {
+    /// Newly grabbed.
+    Added,
+    /// Released because it is no longer in the desired set.
+    Removed,
+    /// Released and grabbed again with a different shortcut.
+    Updated,
+    /// Already grabbed with the same shortcut; left alone.
+    Unchanged,
+    /// Could not be added, updated or removed; see `error`.
+    Failed,
+}
+
+/// One of the requesting client's registered hotkeys.
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct HotkeyInfo {
+    pub id: String,
+    /// The shortcut exactly as it was registered.
+    pub shortcut: String,
+    /// The shortcut as the agent understood it, e.g. `shift+control+KeyQ`.
+    pub normalized: String,
+    /// Unix time of the registration, in milliseconds.
+    pub registered_at: u64,
+    /// How often the hotkey has been pressed since it was registered.
+    pub fire_count: u64,
+    /// False once `disable`d.
+    pub enabled: bool,
+}
+
+/// A snapshot of the agent as a whole.
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct AgentStatus {
+    pub agent_version: &'static str,
+    pub backend: &'static str,
+    /// Milliseconds since the agent started, on the same clock as `ts`.
+    pub uptime_ms: u64,
+    /// Connected clients.
+    pub clients: usize,
+    /// Number of clients subscribed to each topic.
+    pub subscribers: BTreeMap<Topic, usize>,
+    /// Hotkeys registered across all clients.
+    pub hotkeys: usize,
+    /// Events waiting in client output queues.
+    pub queued_events: usize,
+    /// Whether hotkeys are paused with `pause_all`.
+    pub paused: bool,
+}
+
+/// Wrapper written around every event unless legacy output is requested.
+#[derive(Serialize, JsonSchema)]
+pub struct Envelope<'a> {
+    /// Envelope version, currently always 1.
+    v: u32,
+    /// Per-client sequence number, starting at 0 and incremented per event written.
+    seq: u64,
+    /// Milliseconds since the agent started, from a monotonic clock.
+    ts: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+static LEGACY_FORMAT: AtomicBool = AtomicBool::new(false);
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Switches output back to the pre-envelope shapes (`{"event":...}` and
+/// `{"event_type":"KeyPress",...}`) for consumers that have not migrated yet.
+pub fn set_legacy_format(legacy: bool) {
+    LEGACY_FORMAT.store(legacy, Ordering::SeqCst);
+}
+
+/// Starts the clock used for event timestamps. Called once at startup so that
+/// `ts` counts from launch rather than from the first event.
+pub fn start_clock() {
+    START.get_or_init(Instant::now);
+}
+
+/// Time since the agent started.
+pub fn uptime() -> Duration {
+    START.get_or_init(Instant::now).elapsed()
+}
+
+impl Event {
+    /// The topic a client must be subscribed to in order to receive this
+    /// event, or `None` for replies that are always delivered.
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            Event::Ready { .. } | Event::Result { .. } | Event::Dropped { .. } | Event::ShuttingDown { .. } => None,
+            Event::HotkeyPressed { .. }
+            | Event::HotkeyReleased { .. }
+            | Event::SequencePending { .. }
+            | Event::SequenceCancelled { .. }
+            | Event::CapturedKey { .. }
+            | Event::CaptureEnded { .. } => Some(Topic::Hotkeys),
+            Event::KeyPress { .. } | Event::KeyRelease { .. } => Some(Topic::RawKeys),
+            Event::Error { .. } => Some(Topic::Errors),
+        }
+    }
+
+    /// Only the raw key stream may be sacrificed when a client falls behind;
+    /// hotkeys, errors and replies are always delivered.
+    pub fn is_droppable(&self) -> bool {
+        matches!(self, Event::KeyPress { .. } | Event::KeyRelease { .. })
+    }
+}
+
+/// Serializes an event as one line of output. `seq` is ignored in legacy format.
+pub fn encode(event: &Event, seq: u64) -> serde_json::Result<String> {
+    if LEGACY_FORMAT.load(Ordering::SeqCst) {
+        serde_json::to_string(&event.to_legacy())
+    } else {
+        serde_json::to_string(&Envelope {
+            v: EVENT_VERSION,
+            seq,
+            ts: uptime().as_millis() as u64,
+            event,
+        })
+    }
+}
+
+impl Event {
+    /// The shape events had before the envelope was introduced: raw keys used
+    /// an `event_type` key with `KeyPress`/`KeyRelease`, everything else used `event`.
+    fn to_legacy(&self) -> Value {
+        let mut legacy = Map::new();
+        match self {
+            Event::KeyPress { key } | Event::KeyRelease { key } => {
+                let event_type = if matches!(self, Event::KeyPress { .. }) { "KeyPress" } else { "KeyRelease" };
+                legacy.insert("event_type".into(), event_type.into());
+                legacy.insert("key".into(), key.clone().into());
+            }
+            _ => {
+                if let Ok(Value::Object(fields)) = serde_json::to_value(self) {
+                    for (name, value) in fields {
+                        let name = if name == "type" { "event".to_string() } else { name };
+                        legacy.insert(name, value);
+                    }
+                }
+            }
+        }
+        Value::Object(legacy)
+    }
+}
@@ -1 +1,513 @@
-// This file is no longer needed since global-hotkey handles parsing directly 
\ No newline at end of file
+use crate::keys::Side;
+use crate::layout::{self, Layout};
+use crate::tap::Chord;
+use global_hotkey::hotkey::{Code, HotKey, Modifiers};
+use std::fmt;
+
+/// Modifier names and what they stand for. `CommandOrControl` follows the
+/// platform, as it does in global-hotkey.
+const MODIFIER_NAMES: &[(&str, Modifiers)] = &[
+    ("CONTROL", Modifiers::CONTROL),
+    ("CTRL", Modifiers::CONTROL),
+    ("CTL", Modifiers::CONTROL),
+    ("ALT", Modifiers::ALT),
+    ("OPTION", Modifiers::ALT),
+    ("OPT", Modifiers::ALT),
+    ("SHIFT", Modifiers::SHIFT),
+    ("SUPER", Modifiers::SUPER),
+    ("COMMAND", Modifiers::SUPER),
+    ("CMD", Modifiers::SUPER),
+    ("META", Modifiers::SUPER),
+    ("WIN", Modifiers::SUPER),
+    #[cfg(target_os = "macos")]
+    ("COMMANDORCONTROL", Modifiers::SUPER),
+    #[cfg(target_os = "macos")]
+    ("CMDORCTRL", Modifiers::SUPER),
+    #[cfg(not(target_os = "macos"))]
+    ("COMMANDORCONTROL", Modifiers::CONTROL),
+    #[cfg(not(target_os = "macos"))]
+    ("CMDORCTRL", Modifiers::CONTROL),
+];
+
+/// Key names global-hotkey does not know.
+const KEY_ALIASES: &[(&str, Code)] = &[
+    ("RETURN", Code::Enter),
+    ("DEL", Code::Delete),
+    ("INS", Code::Insert),
+    ("PGUP", Code::PageUp),
+    ("PGDN", Code::PageDown),
+    ("BACKTICK", Code::Backquote),
+];
+
+/// Named keys offered as suggestions, besides letters, digits and function keys.
+const KEY_NAMES: &[&str] = &[
+    "Backquote", "Backslash", "BracketLeft", "BracketRight", "Comma", "Equal", "Minus", "Period", "Quote",
+    "Semicolon", "Slash", "Backspace", "CapsLock", "Enter", "Space", "Tab", "Delete", "End", "Home", "Insert",
+    "PageDown", "PageUp", "PrintScreen", "ScrollLock", "Pause", "ArrowDown", "ArrowLeft", "ArrowRight", "ArrowUp",
+    "Escape", "NumLock", "NumpadAdd", "NumpadDecimal", "NumpadDivide", "NumpadEnter", "NumpadEqual",
+    "NumpadMultiply", "NumpadSubtract", "AudioVolumeDown", "AudioVolumeUp", "AudioVolumeMute", "MediaPlay",
+    "MediaPause", "MediaPlayPause", "MediaStop", "MediaTrackNext", "MediaTrackPrevious",
+];
+
+/// Why a shortcut could not be parsed, pointing at the token at fault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub token: String,
+    /// Where the token starts, in characters from the start of the shortcut.
+    pub position: usize,
+    /// Known names close to the token, best first.
+    pub suggestions: Vec<String>,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>, token: &str, position: usize) -> Self {
+        Self { message: message.into(), token: token.to_string(), position, suggestions: Vec::new() }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)?;
+        if !self.suggestions.is_empty() {
+            write!(f, ", did you mean {}?", self.suggestions.join(" or "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A key as a shortcut names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyName {
+    /// A `KeyboardEvent.code` name, grabbed the way global-hotkey does, which
+    /// on X11 is wherever the layout puts that code's US character.
+    Code(Code),
+    /// `code:KeyZ`: the key at that position, whatever it types.
+    Physical(Code),
+    /// `char:z`: whichever key types that character in the active layout.
+    Char(char),
+}
+
+impl fmt::Display for KeyName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyName::Code(code) => write!(f, "{}", code),
+            KeyName::Physical(code) => write!(f, "code:{}", code),
+            KeyName::Char(c) => write!(f, "char:{}", c),
+        }
+    }
+}
+
+/// One step of a shortcut as written: like a `Chord`, except that its key may
+/// depend on the keyboard layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub modifiers: Modifiers,
+    pub left: Modifiers,
+    pub right: Modifiers,
+    pub key: Option<KeyName>,
+}
+
+impl Step {
+    /// Whether the key depends on the layout, so that the step must be
+    /// resolved again when the layout changes.
+    pub fn types_char(&self) -> bool {
+        matches!(self.key, Some(KeyName::Char(_)))
+    }
+
+    /// The chord as the raw key stream sees it, which names keys by position.
+    /// Without a keymap, characters are looked up on a US layout.
+    pub fn typed(&self, layout: Option<&Layout>) -> Result<Chord, String> {
+        match self.key {
+            None => Ok(self.chord(None)),
+            Some(KeyName::Code(code) | KeyName::Physical(code)) => Ok(self.chord(Some(code))),
+            Some(KeyName::Char(c)) => {
+                let (keycode, shifted) = locate(layout, c)?;
+                let code = layout::physical_code(keycode)
+                    .ok_or_else(|| format!("\"{}\" is typed by a key without a KeyboardEvent.code", c))?;
+                Ok(self.shifted(shifted).chord(Some(code)))
+            }
+        }
+    }
+
+    /// The chord to grab. global-hotkey grabs a code wherever the keymap puts
+    /// its US character, so physical and typed keys are grabbed through the
+    /// code that leads to them.
+    pub fn grabbed(&self, layout: Option<&Layout>) -> Result<Chord, String> {
+        let Some(layout) = layout else { return self.typed(None) };
+        let (step, keycode) = match self.key {
+            None | Some(KeyName::Code(_)) => return self.typed(None),
+            Some(KeyName::Physical(code)) => match layout::physical_keycode(code) {
+                Some(keycode) => (*self, keycode),
+                // Keys like F5 are found the same way on every layout
+                None => return self.typed(None),
+            },
+            Some(KeyName::Char(c)) => {
+                let (keycode, shifted) = locate(Some(layout), c)?;
+                (self.shifted(shifted), keycode)
+            }
+        };
+        let code = layout.code_for(keycode).ok_or_else(|| {
+            format!("{} cannot be grabbed with the current keyboard layout", self.key.expect("has a key"))
+        })?;
+        Ok(step.chord(Some(code)))
+    }
+
+    fn chord(&self, key: Option<Code>) -> Chord {
+        Chord { modifiers: self.modifiers, left: self.left, right: self.right, key }
+    }
+
+    fn shifted(mut self, shifted: bool) -> Self {
+        if shifted {
+            self.modifiers |= Modifiers::SHIFT;
+        }
+        self
+    }
+}
+
+impl fmt::Display for Step {
+    /// Spelled like `Chord`, with `code:` and `char:` keys kept as written.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.key {
+            None => write!(f, "{}", self.chord(None)),
+            Some(KeyName::Code(code)) => write!(f, "{}", self.chord(Some(code))),
+            Some(key) if self.modifiers.is_empty() => write!(f, "{}", key),
+            Some(key) => write!(f, "{}+{}", self.chord(None), key),
+        }
+    }
+}
+
+/// The key that types `c`, and whether it takes Shift.
+fn locate(layout: Option<&Layout>, c: char) -> Result<(u8, bool), String> {
+    let located = match layout {
+        Some(layout) => layout.locate(c),
+        None => Layout::us().locate(c),
+    };
+    located.ok_or_else(|| format!("No key types \"{}\" in the current keyboard layout", c))
+}
+
+/// Parses a shortcut into its steps: `Control+Alt+K` has one, the sequence
+/// `Control+K S` has two. Names are case-insensitive and may be spaced out
+/// around `+`. Modifiers may be spelled by any of their aliases, and tied to
+/// a side, like `LeftShift`, `ShiftRight`, `RCtrl` or `AltGr`, in shortcuts
+/// of modifiers alone. A key may be given by position, as `code:KeyZ`, or by
+/// what it types, as `char:z`. Every step of a sequence needs a key.
+pub fn parse(shortcut: &str) -> Result<Vec<Step>, ParseError> {
+    let steps = split_steps(shortcut);
+    if steps.is_empty() {
+        return Err(ParseError::new("Empty shortcut", "", 0));
+    }
+    let parsed = steps.iter().map(|step| parse_step(step)).collect::<Result<Vec<_>, _>>()?;
+    if parsed.len() > 1 {
+        if let Some(index) = parsed.iter().position(|step| step.key.is_none()) {
+            let (position, text) = step_text(&steps[index]);
+            return Err(ParseError::new("Every step of a sequence needs a key besides modifiers", &text, position));
+        }
+    }
+    Ok(parsed)
+}
+
+/// The canonical spelling of parsed steps, as `normalize_shortcut` reports it.
+pub fn canonical(steps: &[Step]) -> String {
+    steps.iter().map(Step::to_string).collect::<Vec<_>>().join(" ")
+}
+
+/// The characters of each step, with their positions. Spaces separate steps,
+/// except around `+`, so `Control + K S` has two.
+fn split_steps(shortcut: &str) -> Vec<Vec<(usize, char)>> {
+    let mut steps: Vec<Vec<(usize, char)>> = Vec::new();
+    let mut word = Vec::new();
+    let end = shortcut.chars().count();
+    for (position, c) in shortcut.chars().enumerate().chain([(end, ' ')]) {
+        if !c.is_whitespace() {
+            word.push((position, c));
+            continue;
+        }
+        if word.is_empty() {
+            continue;
+        }
+        let word = std::mem::take(&mut word);
+        match steps.last_mut() {
+            Some(step) if step.last().is_some_and(|(_, c)| *c == '+') || word[0].1 == '+' => step.extend(word),
+            _ => steps.push(word),
+        }
+    }
+    steps
+}
+
+fn step_text(step: &[(usize, char)]) -> (usize, String) {
+    (step[0].0, step.iter().map(|(_, c)| c).collect())
+}
+
+fn parse_step(step: &[(usize, char)]) -> Result<Step, ParseError> {
+    // The names between the `+`s, with where each starts
+    let mut tokens: Vec<(usize, String)> = vec![(step[0].0, String::new())];
+    for &(position, c) in step {
+        if c == '+' {
+            tokens.push((position + 1, String::new()));
+        } else {
+            tokens.last_mut().expect("never empty").1.push(c);
+        }
+    }
+
+    let mut parsed = Step { modifiers: Modifiers::empty(), left: Modifiers::empty(), right: Modifiers::empty(), key: None };
+    let mut sided: Option<(usize, &str)> = None;
+    for (position, token) in &tokens {
+        let position = *position;
+        if token.is_empty() {
+            let message = if position == step[0].0 { "Missing key before +" } else { "Missing key after +" };
+            return Err(ParseError::new(message, token, position));
+        }
+
+        if let Some((modifier, side)) = parse_modifier(token) {
+            let taken = parsed.modifiers.contains(modifier)
+                && match side {
+                    None => true,
+                    Some(Side::Left) => parsed.left.contains(modifier) || !parsed.right.contains(modifier),
+                    Some(Side::Right) => parsed.right.contains(modifier) || !parsed.left.contains(modifier),
+                };
+            if taken {
+                return Err(ParseError::new(format!("Duplicate modifier \"{}\"", token), token, position));
+            }
+            parsed.modifiers |= modifier;
+            match side {
+                Some(Side::Left) => parsed.left |= modifier,
+                Some(Side::Right) => parsed.right |= modifier,
+                None => {}
+            }
+            if side.is_some() && sided.is_none() {
+                sided = Some((position, token));
+            }
+        } else {
+            let key = parse_key_name(token, position)?;
+            if let Some(first) = parsed.key {
+                return Err(ParseError::new(format!("Second key \"{}\" after {}", token, first), token, position));
+            }
+            parsed.key = Some(key);
+        }
+    }
+
+    // Grabs cannot tell the sides apart; only the raw key stream can
+    if let (Some(_), Some((position, token))) = (parsed.key, sided) {
+        return Err(ParseError::new(
+            format!("\"{}\" picks a side, which only works in shortcuts of modifiers alone", token),
+            token,
+            position,
+        ));
+    }
+    Ok(parsed)
+}
+
+fn modifier_name(name: &str) -> Option<Modifiers> {
+    MODIFIER_NAMES.iter().find(|(alias, _)| *alias == name).map(|(_, modifier)| *modifier)
+}
+
+/// A modifier, possibly tied to a side by a `Left`/`Right` prefix or suffix,
+/// an `L`/`R` prefix, or as `AltGr`.
+fn parse_modifier(token: &str) -> Option<(Modifiers, Option<Side>)> {
+    let token = token.to_uppercase();
+    if token == "ALTGR" {
+        return Some((Modifiers::ALT, Some(Side::Right)));
+    }
+    if let Some(modifier) = modifier_name(&token) {
+        return Some((modifier, None));
+    }
+    for (name, side) in [("LEFT", Side::Left), ("RIGHT", Side::Right)] {
+        let short = &name[..1];
+        let modifier = token
+            .strip_prefix(name)
+            .or_else(|| token.strip_suffix(name))
+            .or_else(|| token.strip_prefix(short))
+            .and_then(modifier_name);
+        if let Some(modifier) = modifier {
+            return Some((modifier, Some(side)));
+        }
+    }
+    None
+}
+
+/// A key name, possibly behind a `code:` or `char:` prefix.
+fn parse_key_name(token: &str, position: usize) -> Result<KeyName, ParseError> {
+    let prefix = |name: &str| token.get(..name.len()).filter(|start| start.eq_ignore_ascii_case(name)).map(|_| &token[name.len()..]);
+    if let Some(c) = prefix("char:") {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(KeyName::Char(c)),
+            _ => Err(ParseError::new("char: takes a single character", token, position)),
+        };
+    }
+    let (name, position, physical) = match prefix("code:") {
+        Some(name) => (name, position + "code:".len(), true),
+        None => (token, position, false),
+    };
+    match parse_key(name) {
+        Some(code) if physical => Ok(KeyName::Physical(code)),
+        Some(code) => Ok(KeyName::Code(code)),
+        None => {
+            let mut error = ParseError::new(format!("Unknown key \"{}\"", name), name, position);
+            error.suggestions = suggestions(name);
+            Err(error)
+        }
+    }
+}
+
+fn parse_key(token: &str) -> Option<Code> {
+    let upper = token.to_uppercase();
+    if let Some((_, code)) = KEY_ALIASES.iter().find(|(alias, _)| *alias == upper) {
+        return Some(*code);
+    }
+    // A lone key name parses as a hotkey without modifiers
+    token.parse::<HotKey>().ok().filter(|hotkey| hotkey.mods.is_empty()).map(|hotkey| hotkey.key)
+}
+
+/// Known names within a couple of typos of `token`, closest first.
+fn suggestions(token: &str) -> Vec<String> {
+    let upper = token.to_uppercase();
+    let max_distance = if upper.chars().count() <= 3 { 1 } else { 2 };
+
+    let mut candidates: Vec<(String, String)> = Vec::new();
+    for (alias, modifier) in MODIFIER_NAMES {
+        let name = modifier_display(*modifier);
+        candidates.push((alias.to_string(), name.to_string()));
+        for side in ["Left", "Right"] {
+            candidates.push((format!("{}{}", side.to_uppercase(), alias), format!("{}{}", side, name)));
+        }
+    }
+    candidates.push(("ALTGR".to_string(), "AltGr".to_string()));
+    let generated = ('A'..='Z')
+        .map(|letter| format!("Key{}", letter))
+        .chain((0..=9).map(|digit| format!("Digit{}", digit)))
+        .chain((0..=9).map(|digit| format!("Numpad{}", digit)))
+        .chain((1..=24).map(|number| format!("F{}", number)));
+    for name in KEY_NAMES.iter().map(|name| name.to_string()).chain(generated) {
+        candidates.push((name.to_uppercase(), name));
+    }
+    // Short names global-hotkey knows, on top of ours
+    let short_names = [("ESC", Code::Escape), ("UP", Code::ArrowUp), ("DOWN", Code::ArrowDown), ("LEFT", Code::ArrowLeft), ("RIGHT", Code::ArrowRight)];
+    for (alias, code) in KEY_ALIASES.iter().chain(&short_names) {
+        candidates.push((alias.to_string(), code.to_string()));
+    }
+
+    let mut close: Vec<(usize, String)> = candidates
+        .into_iter()
+        .map(|(spelling, name)| (distance(&upper, &spelling), name))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    // Stable, so equally close names keep the order above
+    close.sort_by_key(|(distance, _)| *distance);
+    let mut names: Vec<String> = Vec::new();
+    for (_, name) in close {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.truncate(3);
+    names
+}
+
+fn modifier_display(modifier: Modifiers) -> &'static str {
+    match modifier {
+        Modifiers::CONTROL => "Control",
+        Modifiers::ALT => "Alt",
+        Modifiers::SHIFT => "Shift",
+        _ => "Super",
+    }
+}
+
+/// Edits needed to turn `a` into `b`, counting a swap of neighbours as one.
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    rows[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1).min(rows[i][j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(shortcut: &str) -> Result<String, ParseError> {
+        parse(shortcut).map(|steps| canonical(&steps))
+    }
+
+    #[test]
+    fn aliases_case_and_spacing_normalize_alike() {
+        for shortcut in ["Control+Alt+Q", "ctrl+opt+q", "CTL + Option + KeyQ", " alt+control+q "] {
+            assert_eq!(normalize(shortcut).unwrap(), "control+alt+KeyQ", "{}", shortcut);
+        }
+        assert_eq!(normalize("cmd+Shift+Del").unwrap(), "shift+super+Delete");
+        assert_eq!(normalize("win+meta+k"), Err(ParseError::new("Duplicate modifier \"meta\"", "meta", 4)));
+        assert_eq!(normalize("Control + K  s").unwrap(), "control+KeyK KeyS");
+    }
+
+    #[test]
+    fn sides_only_in_modifier_only_shortcuts() {
+        assert_eq!(normalize("RightAlt").unwrap(), "rightalt");
+        assert_eq!(normalize("altgr").unwrap(), normalize("AltRight").unwrap());
+        assert_eq!(normalize("RCtrl").unwrap(), "rightcontrol");
+        assert_eq!(normalize("Shift+LeftSuper").unwrap(), "shift+leftsuper");
+        assert_eq!(normalize("MetaLeft").unwrap(), normalize("leftwin").unwrap());
+        assert_eq!(normalize("LeftControl+RightControl").unwrap(), "leftcontrol+rightcontrol");
+        assert_eq!(parse("LeftControl+RightControl").unwrap()[0].right, Modifiers::CONTROL);
+        assert_eq!(normalize("Left").unwrap(), "ArrowLeft");
+
+        let error = normalize("Shift+LeftCtrl+K").unwrap_err();
+        assert_eq!((error.token.as_str(), error.position), ("LeftCtrl", 6));
+    }
+
+    #[test]
+    fn typos_point_at_the_token_with_suggestions() {
+        let error = normalize("Contrl+K").unwrap_err();
+        assert_eq!(error.to_string(), "Unknown key \"Contrl\" at position 0, did you mean Control?");
+
+        let error = normalize("Alt+Shfit+F13").unwrap_err();
+        assert_eq!((error.token.as_str(), error.position), ("Shfit", 4));
+        assert_eq!(error.suggestions, vec!["Shift"]);
+
+        let error = normalize("Control+K Escpe").unwrap_err();
+        assert_eq!((error.position, error.suggestions.as_slice()), (10, &["Escape".to_string()][..]));
+
+        assert_eq!(normalize("Shift+Lft").unwrap_err().suggestions, vec!["ArrowLeft"]);
+        assert!(normalize("Control+Xyzzy").unwrap_err().suggestions.is_empty());
+    }
+
+    #[test]
+    fn physical_and_typed_keys() {
+        assert_eq!(normalize("Control+CODE:z").unwrap(), "control+code:KeyZ");
+        assert_eq!(normalize("alt+char:?").unwrap(), "alt+char:?");
+        assert_eq!(normalize("code:Escape").unwrap(), "code:Escape");
+
+        // Without a keymap, characters are where a US keyboard has them
+        let step = parse("Control+char:?").unwrap()[0];
+        assert_eq!(step.grabbed(None).unwrap().to_string(), "shift+control+Slash");
+        assert_eq!(parse("char:z").unwrap()[0].typed(None).unwrap().key, Some(Code::KeyZ));
+        assert!(parse("char:é").unwrap()[0].typed(None).is_err());
+
+        let error = normalize("Control+code:Kez").unwrap_err();
+        assert_eq!((error.token.as_str(), error.position, error.suggestions.as_slice()), ("Kez", 13, &["KeyZ".to_string()][..]));
+        assert_eq!(normalize("char:ab").unwrap_err(), ParseError::new("char: takes a single character", "char:ab", 0));
+    }
+
+    #[test]
+    fn malformed_shortcuts() {
+        assert_eq!(normalize("  ").unwrap_err().message, "Empty shortcut");
+        assert_eq!(normalize("Control+").unwrap_err(), ParseError::new("Missing key after +", "", 8));
+        assert_eq!(normalize("+K").unwrap_err().message, "Missing key before +");
+        assert_eq!(normalize("Control+K+J").unwrap_err(), ParseError::new("Second key \"J\" after KeyK", "J", 10));
+        assert_eq!(normalize("Control+K Shift").unwrap_err().position, 10);
+    }
+}
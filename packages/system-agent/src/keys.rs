@@ -0,0 +1,150 @@
+use global_hotkey::hotkey::{Code, Modifiers};
+use rdev::Key;
+
+/// Which of a pair of modifier keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// The modifier an rdev key stands for, if it is one. Left and right
+/// variants map to the same modifier, as they do for grabbed hotkeys.
+pub fn modifier(key: Key) -> Option<Modifiers> {
+    match key {
+        Key::ShiftLeft | Key::ShiftRight => Some(Modifiers::SHIFT),
+        Key::ControlLeft | Key::ControlRight => Some(Modifiers::CONTROL),
+        Key::Alt | Key::AltGr => Some(Modifiers::ALT),
+        Key::MetaLeft | Key::MetaRight => Some(Modifiers::SUPER),
+        _ => None,
+    }
+}
+
+/// The side of the keyboard a modifier key is on. Right Alt is reported as
+/// AltGr on most layouts.
+pub fn side(key: Key) -> Option<Side> {
+    match key {
+        Key::ShiftLeft | Key::ControlLeft | Key::Alt | Key::MetaLeft => Some(Side::Left),
+        Key::ShiftRight | Key::ControlRight | Key::AltGr | Key::MetaRight => Some(Side::Right),
+        _ => None,
+    }
+}
+
+/// The `KeyboardEvent.code` of a non-modifier rdev key, as used in shortcuts.
+pub fn code(key: Key) -> Option<Code> {
+    let code = match key {
+        Key::KeyA => Code::KeyA,
+        Key::KeyB => Code::KeyB,
+        Key::KeyC => Code::KeyC,
+        Key::KeyD => Code::KeyD,
+        Key::KeyE => Code::KeyE,
+        Key::KeyF => Code::KeyF,
+        Key::KeyG => Code::KeyG,
+        Key::KeyH => Code::KeyH,
+        Key::KeyI => Code::KeyI,
+        Key::KeyJ => Code::KeyJ,
+        Key::KeyK => Code::KeyK,
+        Key::KeyL => Code::KeyL,
+        Key::KeyM => Code::KeyM,
+        Key::KeyN => Code::KeyN,
+        Key::KeyO => Code::KeyO,
+        Key::KeyP => Code::KeyP,
+        Key::KeyQ => Code::KeyQ,
+        Key::KeyR => Code::KeyR,
+        Key::KeyS => Code::KeyS,
+        Key::KeyT => Code::KeyT,
+        Key::KeyU => Code::KeyU,
+        Key::KeyV => Code::KeyV,
+        Key::KeyW => Code::KeyW,
+        Key::KeyX => Code::KeyX,
+        Key::KeyY => Code::KeyY,
+        Key::KeyZ => Code::KeyZ,
+        Key::Num0 => Code::Digit0,
+        Key::Num1 => Code::Digit1,
+        Key::Num2 => Code::Digit2,
+        Key::Num3 => Code::Digit3,
+        Key::Num4 => Code::Digit4,
+        Key::Num5 => Code::Digit5,
+        Key::Num6 => Code::Digit6,
+        Key::Num7 => Code::Digit7,
+        Key::Num8 => Code::Digit8,
+        Key::Num9 => Code::Digit9,
+        Key::F1 => Code::F1,
+        Key::F2 => Code::F2,
+        Key::F3 => Code::F3,
+        Key::F4 => Code::F4,
+        Key::F5 => Code::F5,
+        Key::F6 => Code::F6,
+        Key::F7 => Code::F7,
+        Key::F8 => Code::F8,
+        Key::F9 => Code::F9,
+        Key::F10 => Code::F10,
+        Key::F11 => Code::F11,
+        Key::F12 => Code::F12,
+        Key::BackQuote => Code::Backquote,
+        Key::Minus => Code::Minus,
+        Key::Equal => Code::Equal,
+        Key::LeftBracket => Code::BracketLeft,
+        Key::RightBracket => Code::BracketRight,
+        Key::BackSlash => Code::Backslash,
+        Key::IntlBackslash => Code::IntlBackslash,
+        Key::SemiColon => Code::Semicolon,
+        Key::Quote => Code::Quote,
+        Key::Comma => Code::Comma,
+        Key::Dot => Code::Period,
+        Key::Slash => Code::Slash,
+        Key::Space => Code::Space,
+        Key::Tab => Code::Tab,
+        Key::Return => Code::Enter,
+        Key::Backspace => Code::Backspace,
+        Key::Escape => Code::Escape,
+        Key::CapsLock => Code::CapsLock,
+        Key::Insert => Code::Insert,
+        Key::Delete => Code::Delete,
+        Key::Home => Code::Home,
+        Key::End => Code::End,
+        Key::PageUp => Code::PageUp,
+        Key::PageDown => Code::PageDown,
+        Key::UpArrow => Code::ArrowUp,
+        Key::DownArrow => Code::ArrowDown,
+        Key::LeftArrow => Code::ArrowLeft,
+        Key::RightArrow => Code::ArrowRight,
+        Key::PrintScreen => Code::PrintScreen,
+        Key::ScrollLock => Code::ScrollLock,
+        Key::Pause => Code::Pause,
+        Key::NumLock => Code::NumLock,
+        Key::Kp0 => Code::Numpad0,
+        Key::Kp1 => Code::Numpad1,
+        Key::Kp2 => Code::Numpad2,
+        Key::Kp3 => Code::Numpad3,
+        Key::Kp4 => Code::Numpad4,
+        Key::Kp5 => Code::Numpad5,
+        Key::Kp6 => Code::Numpad6,
+        Key::Kp7 => Code::Numpad7,
+        Key::Kp8 => Code::Numpad8,
+        Key::Kp9 => Code::Numpad9,
+        Key::KpReturn => Code::NumpadEnter,
+        Key::KpMinus => Code::NumpadSubtract,
+        Key::KpPlus => Code::NumpadAdd,
+        Key::KpMultiply => Code::NumpadMultiply,
+        Key::KpDivide => Code::NumpadDivide,
+        Key::KpDelete => Code::NumpadDecimal,
+        Key::Function => Code::Fn,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// The names of the modifiers in `modifiers`, as they appear in normalized shortcuts.
+pub fn modifier_names(modifiers: Modifiers) -> Vec<&'static str> {
+    [
+        (Modifiers::CONTROL, "control"),
+        (Modifiers::ALT, "alt"),
+        (Modifiers::SHIFT, "shift"),
+        (Modifiers::SUPER, "super"),
+    ]
+    .into_iter()
+    .filter(|(modifier, _)| modifiers.contains(*modifier))
+    .map(|(_, name)| name)
+    .collect()
+}
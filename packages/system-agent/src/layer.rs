@@ -0,0 +1,114 @@
+use crate::cmd::RegisterCommand;
+use std::collections::{HashMap, HashSet};
+
+/// A client's named layers of hotkeys, and the stack of the active ones.
+/// `S` is whatever an active layer needs to remember to be popped again.
+#[derive(Debug)]
+pub struct Keymap<S> {
+    layers: HashMap<String, Vec<RegisterCommand>>,
+    /// Active layers, bottom first.
+    stack: Vec<(String, S)>,
+}
+
+impl<S> Default for Keymap<S> {
+    fn default() -> Self {
+        Self { layers: HashMap::new(), stack: Vec::new() }
+    }
+}
+
+impl<S> Keymap<S> {
+    /// Defines or redefines a layer. An active layer keeps the hotkeys it was
+    /// pushed with until it is popped.
+    pub fn define(&mut self, name: &str, hotkeys: Vec<RegisterCommand>) -> Result<(), String> {
+        if name.is_empty() {
+            return Err("Layer name cannot be empty".to_string());
+        }
+        let mut ids = HashSet::new();
+        if let Some(duplicate) = hotkeys.iter().find(|binding| !ids.insert(binding.id.as_str())) {
+            return Err(format!("Duplicate id in layer {}: {}", name, duplicate.id));
+        }
+        self.layers.insert(name.to_string(), hotkeys);
+        Ok(())
+    }
+
+    /// The hotkeys of a layer that can be pushed now.
+    pub fn pushable(&self, name: &str) -> Result<&[RegisterCommand], String> {
+        if self.stack.iter().any(|(active, _)| active == name) {
+            return Err(format!("Layer {} is already active", name));
+        }
+        self.layers
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| format!("Unknown layer: {}", name))
+    }
+
+    pub fn push(&mut self, name: &str, state: S) {
+        self.stack.push((name.to_string(), state));
+    }
+
+    /// Pops the top layer, which must be `name` if one is given.
+    pub fn pop(&mut self, name: Option<&str>) -> Result<(String, S), String> {
+        let top = self.stack.last().map(|(top, _)| top.as_str());
+        match (top, name) {
+            (None, _) => Err("No layer is active".to_string()),
+            (Some(top), Some(name)) if top != name => {
+                Err(format!("Layer {} is not on top of the stack (the top is {})", name, top))
+            }
+            _ => Ok(self.stack.pop().expect("checked above")),
+        }
+    }
+
+    /// The names of the active layers, bottom first.
+    pub fn active(&self) -> Vec<String> {
+        self.stack.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// What the active layers remember, bottom first.
+    pub fn active_states(&self) -> impl Iterator<Item = &S> {
+        self.stack.iter().map(|(_, state)| state)
+    }
+
+    pub fn active_mut(&mut self) -> impl Iterator<Item = &mut S> {
+        self.stack.iter_mut().map(|(_, state)| state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hotkeys(ids: &[&str]) -> Vec<RegisterCommand> {
+        ids.iter()
+            .map(|id| serde_json::from_value(json!({ "id": id, "shortcut": "Control+Alt+K" })).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn define_rejects_duplicate_ids() {
+        let mut keymap: Keymap<()> = Keymap::default();
+
+        assert!(keymap.define("edit", hotkeys(&["save", "save"])).is_err());
+        assert!(keymap.pushable("edit").is_err());
+        assert!(keymap.define("edit", hotkeys(&["save", "close"])).is_ok());
+        assert_eq!(keymap.pushable("edit").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn layers_push_once_and_pop_from_the_top() {
+        let mut keymap = Keymap::default();
+        keymap.define("edit", hotkeys(&["save"])).unwrap();
+        keymap.define("spellbook", hotkeys(&["cast"])).unwrap();
+
+        assert!(keymap.pop(None).is_err());
+        keymap.push("edit", 1);
+        assert!(keymap.pushable("edit").is_err());
+        keymap.push("spellbook", 2);
+        assert_eq!(keymap.active(), vec!["edit", "spellbook"]);
+
+        assert!(keymap.pop(Some("edit")).is_err());
+        assert_eq!(keymap.pop(Some("spellbook")), Ok(("spellbook".to_string(), 2)));
+        assert_eq!(keymap.pop(None), Ok(("edit".to_string(), 1)));
+        assert!(keymap.active().is_empty());
+    }
+}
@@ -0,0 +1,351 @@
+use global_hotkey::hotkey::Code;
+
+/// Keys whose character depends on the layout: their `KeyboardEvent.code`,
+/// their evdev scancode, and what they type on a US keyboard, unshifted and shifted.
+const CHARACTER_KEYS: &[(Code, u8, char, char)] = &[
+    (Code::Backquote, 41, '`', '~'),
+    (Code::Digit1, 2, '1', '!'),
+    (Code::Digit2, 3, '2', '@'),
+    (Code::Digit3, 4, '3', '#'),
+    (Code::Digit4, 5, '4', '$'),
+    (Code::Digit5, 6, '5', '%'),
+    (Code::Digit6, 7, '6', '^'),
+    (Code::Digit7, 8, '7', '&'),
+    (Code::Digit8, 9, '8', '*'),
+    (Code::Digit9, 10, '9', '('),
+    (Code::Digit0, 11, '0', ')'),
+    (Code::Minus, 12, '-', '_'),
+    (Code::Equal, 13, '=', '+'),
+    (Code::KeyQ, 16, 'q', 'Q'),
+    (Code::KeyW, 17, 'w', 'W'),
+    (Code::KeyE, 18, 'e', 'E'),
+    (Code::KeyR, 19, 'r', 'R'),
+    (Code::KeyT, 20, 't', 'T'),
+    (Code::KeyY, 21, 'y', 'Y'),
+    (Code::KeyU, 22, 'u', 'U'),
+    (Code::KeyI, 23, 'i', 'I'),
+    (Code::KeyO, 24, 'o', 'O'),
+    (Code::KeyP, 25, 'p', 'P'),
+    (Code::BracketLeft, 26, '[', '{'),
+    (Code::BracketRight, 27, ']', '}'),
+    (Code::KeyA, 30, 'a', 'A'),
+    (Code::KeyS, 31, 's', 'S'),
+    (Code::KeyD, 32, 'd', 'D'),
+    (Code::KeyF, 33, 'f', 'F'),
+    (Code::KeyG, 34, 'g', 'G'),
+    (Code::KeyH, 35, 'h', 'H'),
+    (Code::KeyJ, 36, 'j', 'J'),
+    (Code::KeyK, 37, 'k', 'K'),
+    (Code::KeyL, 38, 'l', 'L'),
+    (Code::Semicolon, 39, ';', ':'),
+    (Code::Quote, 40, '\'', '"'),
+    (Code::Backslash, 43, '\\', '|'),
+    (Code::KeyZ, 44, 'z', 'Z'),
+    (Code::KeyX, 45, 'x', 'X'),
+    (Code::KeyC, 46, 'c', 'C'),
+    (Code::KeyV, 47, 'v', 'V'),
+    (Code::KeyB, 48, 'b', 'B'),
+    (Code::KeyN, 49, 'n', 'N'),
+    (Code::KeyM, 50, 'm', 'M'),
+    (Code::Comma, 51, ',', '<'),
+    (Code::Period, 52, '.', '>'),
+    (Code::Slash, 53, '/', '?'),
+    (Code::Space, 57, ' ', ' '),
+];
+
+/// X keycodes are evdev scancodes shifted by 8.
+const EVDEV_OFFSET: u8 = 8;
+
+/// The X keycode of the key at `code`'s position, for keys that type characters.
+pub fn physical_keycode(code: Code) -> Option<u8> {
+    CHARACTER_KEYS.iter().find(|(key, ..)| *key == code).map(|(_, scancode, ..)| scancode + EVDEV_OFFSET)
+}
+
+/// The `KeyboardEvent.code` of the key with this X keycode, for keys that type characters.
+pub fn physical_code(keycode: u8) -> Option<Code> {
+    CHARACTER_KEYS
+        .iter()
+        .find(|(_, scancode, ..)| scancode + EVDEV_OFFSET == keycode)
+        .map(|(code, ..)| *code)
+}
+
+/// The keysym global-hotkey looks for to grab `code` on X11: a letter's
+/// capital, or what the key types unshifted on a US keyboard.
+fn grab_keysym(code: Code) -> Option<u32> {
+    let (_, _, unshifted, shifted) = CHARACTER_KEYS.iter().find(|(key, ..)| *key == code)?;
+    Some(match code {
+        _ if unshifted.is_ascii_alphabetic() => *shifted as u32,
+        Code::Quote => xkeysym::key::leftsinglequotemark,
+        _ => *unshifted as u32,
+    })
+}
+
+/// A snapshot of the keyboard mapping: which keysyms each key carries, and
+/// which group (layout) is active. global-hotkey grabs a code on the first
+/// key carrying its keysym, whatever the layout, so this is what tells which
+/// key a code ends up on, and which code to ask for to get a given key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    min_keycode: u8,
+    /// Keysyms of each key from `min_keycode` on, `per_keycode` at a time, in
+    /// the core protocol's order: two levels of the first group, then two of the second.
+    per_keycode: usize,
+    keysyms: Vec<u32>,
+    group: usize,
+}
+
+impl Layout {
+    pub fn new(min_keycode: u8, per_keycode: usize, keysyms: Vec<u32>, group: usize) -> Self {
+        Self { min_keycode, per_keycode: per_keycode.max(1), keysyms, group }
+    }
+
+    /// A US keyboard, for shortcuts resolved where there is no keymap to ask.
+    pub fn us() -> Self {
+        let max = CHARACTER_KEYS.iter().map(|(_, scancode, ..)| *scancode).max().unwrap_or(0);
+        let mut keysyms = vec![0; 2 * (usize::from(max) + 1)];
+        for (_, scancode, unshifted, shifted) in CHARACTER_KEYS {
+            let index = 2 * usize::from(*scancode);
+            keysyms[index] = *unshifted as u32;
+            keysyms[index + 1] = *shifted as u32;
+        }
+        Self::new(EVDEV_OFFSET, 2, keysyms, 0)
+    }
+
+    fn keys(&self) -> impl Iterator<Item = (u8, &[u32])> {
+        self.keysyms
+            .chunks(self.per_keycode)
+            .enumerate()
+            .map(|(index, keysyms)| (self.min_keycode.saturating_add(index as u8), keysyms))
+    }
+
+    /// The key that types `c` in the active group, and whether it takes Shift.
+    /// Keys that type it unshifted are preferred.
+    pub fn locate(&self, c: char) -> Option<(u8, bool)> {
+        // Groups past the second are not laid out in pairs in the core mapping
+        let group = if 2 * self.group + 1 < self.per_keycode.min(4) { self.group } else { 0 };
+        for shifted in [false, true] {
+            let column = 2 * group + usize::from(shifted);
+            let found = self.keys().find(|(_, keysyms)| {
+                keysyms
+                    .get(column)
+                    .and_then(|keysym| xkeysym::Keysym::new(*keysym).key_char())
+                    .is_some_and(|typed| typed == c)
+            });
+            if let Some((keycode, _)) = found {
+                return Some((keycode, shifted));
+            }
+        }
+        None
+    }
+
+    /// The key global-hotkey grabs for `code`: the first carrying its keysym.
+    pub fn grabbed_keycode(&self, code: Code) -> Option<u8> {
+        let keysym = grab_keysym(code)?;
+        self.keys().find(|(_, keysyms)| keysyms.contains(&keysym)).map(|(keycode, _)| keycode)
+    }
+
+    /// A code that makes global-hotkey grab the key with this keycode. The
+    /// code of the key's own position is preferred.
+    pub fn code_for(&self, keycode: u8) -> Option<Code> {
+        physical_code(keycode)
+            .into_iter()
+            .chain(CHARACTER_KEYS.iter().map(|(code, ..)| *code))
+            .find(|code| self.grabbed_keycode(*code) == Some(keycode))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use x11::watch;
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use super::Layout;
+    use crossbeam_channel::{never, unbounded, Receiver};
+    use std::thread;
+    use x11rb::connection::{Connection, RequestConnection};
+    use x11rb::protocol::xkb::{self, ConnectionExt as _, EventType, MapPart, SelectEventsAux, SelectEventsAuxStateNotify, StatePart};
+    use x11rb::protocol::xproto::ConnectionExt as _;
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+
+    /// Reads the keyboard layout, and keeps reading it again on a thread of
+    /// its own whenever the keymap or the active group changes. Returns the
+    /// current layout, if there is an X server to ask, and the changes to come.
+    pub fn watch() -> (Option<Layout>, Receiver<Layout>) {
+        if crate::agent::display_backend() == "wayland" {
+            return (None, never());
+        }
+        let connection = match connect() {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("[system-agent] Keyboard layout unavailable, char: shortcuts assume a US layout: {}", e);
+                return (None, never());
+            }
+        };
+        let mut current = match load(&connection) {
+            Ok(layout) => layout,
+            Err(e) => {
+                eprintln!("[system-agent] Failed to read the keyboard layout: {}", e);
+                return (None, never());
+            }
+        };
+
+        let (sender, receiver) = unbounded();
+        let initial = current.clone();
+        thread::spawn(move || loop {
+            let event = match connection.wait_for_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    eprintln!("[system-agent] Stopped watching the keyboard layout: {}", e);
+                    return;
+                }
+            };
+            if !matches!(event, Event::MappingNotify(_) | Event::XkbNewKeyboardNotify(_) | Event::XkbMapNotify(_) | Event::XkbStateNotify(_)) {
+                continue;
+            }
+            // One change comes as several events
+            while let Ok(Some(_)) = connection.poll_for_event() {}
+            match load(&connection) {
+                Ok(layout) if layout != current => {
+                    current = layout.clone();
+                    if sender.send(layout).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("[system-agent] Failed to read the keyboard layout: {}", e),
+            }
+        });
+        (Some(initial), receiver)
+    }
+
+    /// Connects and asks for the events that come with a new keymap or a
+    /// switch of group. Without XKB, only core mapping changes are noticed.
+    fn connect() -> Result<RustConnection, String> {
+        let (connection, _) = x11rb::connect(None).map_err(|e| format!("Failed to connect to the X server: {}", e))?;
+        if connection.extension_information(xkb::X11_EXTENSION_NAME).ok().flatten().is_none() {
+            return Ok(connection);
+        }
+        let supported = connection
+            .xkb_use_extension(1, 0)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?
+            .supported;
+        if supported {
+            let details = SelectEventsAux {
+                state_notify: Some(SelectEventsAuxStateNotify { affect_state: StatePart::GROUP_STATE, state_details: StatePart::GROUP_STATE }),
+                ..Default::default()
+            };
+            connection
+                .xkb_select_events(
+                    xkb::ID::USE_CORE_KBD.into(),
+                    EventType::from(0u16),
+                    EventType::NEW_KEYBOARD_NOTIFY | EventType::MAP_NOTIFY,
+                    MapPart::KEY_SYMS,
+                    MapPart::KEY_SYMS,
+                    &details,
+                )
+                .map_err(|e| e.to_string())?
+                .check()
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(connection)
+    }
+
+    fn load(connection: &RustConnection) -> Result<Layout, String> {
+        let setup = connection.setup();
+        let (min_keycode, max_keycode) = (setup.min_keycode, setup.max_keycode);
+        let mapping = connection
+            .get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?;
+        let group = match connection.extension_information(xkb::X11_EXTENSION_NAME).ok().flatten() {
+            Some(_) => connection
+                .xkb_get_state(xkb::ID::USE_CORE_KBD.into())
+                .map_err(|e| e.to_string())?
+                .reply()
+                .map_or(0, |state| usize::from(u8::from(state.group))),
+            None => 0,
+        };
+        Ok(Layout::new(min_keycode, usize::from(mapping.keysyms_per_keycode), mapping.keysyms, group))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch() -> (Option<Layout>, crossbeam_channel::Receiver<Layout>) {
+    (None, crossbeam_channel::never())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An excerpt of a keymap, laid out as the core protocol reports it.
+    fn layout(rows: &[(u8, [char; 4])], group: usize) -> Layout {
+        let mut keysyms = vec![0; 4 * 64];
+        for (keycode, row) in rows {
+            for (level, c) in row.iter().enumerate() {
+                keysyms[4 * usize::from(*keycode - 8) + level] = xkeysym::Keysym::from_char(*c).raw();
+            }
+        }
+        Layout::new(8, 4, keysyms, group)
+    }
+
+    /// The keys that differ between French AZERTY and US, in that order.
+    fn azerty_us(group: usize) -> Layout {
+        layout(&[
+            (10, ['&', '1', '1', '!']),
+            (24, ['a', 'A', 'q', 'Q']),
+            (25, ['z', 'Z', 'w', 'W']),
+            (38, ['q', 'Q', 'a', 'A']),
+            (52, ['w', 'W', 'z', 'Z']),
+            (58, [',', '?', 'm', 'M']),
+            (61, ['!', '§', '/', '?']),
+        ], group)
+    }
+
+    /// French AZERTY alone, which repeats its group where the second would be.
+    fn azerty() -> Layout {
+        layout(&[
+            (24, ['a', 'A', 'a', 'A']),
+            (25, ['z', 'Z', 'z', 'Z']),
+            (38, ['q', 'Q', 'q', 'Q']),
+            (52, ['w', 'W', 'w', 'W']),
+        ], 0)
+    }
+
+    #[test]
+    fn physical_keys_are_evdev_scancodes() {
+        assert_eq!(physical_keycode(Code::KeyZ), Some(52));
+        assert_eq!(physical_code(52), Some(Code::KeyZ));
+        assert_eq!(physical_keycode(Code::F5), None);
+    }
+
+    #[test]
+    fn characters_are_located_in_the_active_group() {
+        let french = azerty_us(0);
+        assert_eq!(french.locate('z'), Some((25, false)));
+        assert_eq!(french.locate('?'), Some((58, true)));
+        assert_eq!(french.locate('1'), Some((10, true)));
+        assert_eq!(french.locate('é'), None);
+
+        let us = azerty_us(1);
+        assert_eq!(us.locate('z'), Some((52, false)));
+        assert_eq!(us.locate('?'), Some((61, true)));
+        assert_eq!(Layout::us().locate('z'), Some((52, false)));
+    }
+
+    #[test]
+    fn codes_are_steered_onto_keys() {
+        let layout = azerty();
+        // global-hotkey looks for Z, which AZERTY has where US has W
+        assert_eq!(layout.grabbed_keycode(Code::KeyZ), physical_keycode(Code::KeyW));
+        assert_eq!(layout.code_for(physical_keycode(Code::KeyW).unwrap()), Some(Code::KeyZ));
+        assert_eq!(layout.code_for(physical_keycode(Code::KeyZ).unwrap()), Some(Code::KeyW));
+        assert_eq!(Layout::us().code_for(52), Some(Code::KeyZ));
+        // With both groups in the mapping, W and Z are found first on the key at KeyW
+        assert_eq!(azerty_us(1).code_for(physical_keycode(Code::KeyZ).unwrap()), None);
+    }
+}
@@ -1,9 +1,50 @@
 mod agent;
+mod capture;
+mod client;
 mod cmd;
+mod condition;
+mod event;
+mod hotkey;
+mod keys;
+mod layer;
+mod layout;
+mod registry;
+mod schema;
+mod sequence;
+#[cfg(unix)]
+mod server;
+mod tap;
+mod trigger;
+mod window;
 
-use agent::SystemAgent;
+use agent::{AgentOptions, SystemAgent};
 
 fn main() {
-    let agent = SystemAgent::new();
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        let schema = schema::protocol_schema();
+        println!("{}", serde_json::to_string_pretty(&schema).expect("schema is valid JSON"));
+        return;
+    }
+
+    let mut options = AgentOptions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--legacy-events" => options.legacy_events = true,
+            "--socket" => match args.next() {
+                Some(path) => options.socket_path = Some(path.into()),
+                None => {
+                    eprintln!("[system-agent] --socket requires a path");
+                    std::process::exit(2);
+                }
+            },
+            other => {
+                eprintln!("[system-agent] Unknown argument: {}", other);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let agent = SystemAgent::new(options);
     agent.run();
 }
@@ -0,0 +1,2013 @@
+use crate::agent::display_backend;
+use crate::capture::{Capture, DEFAULT_CAPTURE_TIMEOUT};
+use crate::client::{ClientId, Clients};
+use crate::cmd::{ConflictPolicy, ContextValue, HotkeyEventKind, RegisterCommand, Trigger};
+use crate::condition::{Condition, Context};
+use crate::event::{self, AgentStatus, CaptureEndReason, Event, HotkeyFailure, HotkeyInfo, SequenceCancelReason, SyncAction, SyncChange};
+use crate::hotkey::{self, Step};
+use crate::keys;
+use crate::layer::Keymap;
+use crate::layout::{self, Layout};
+use crate::sequence::{Cancelled, Progress, SequenceMatcher, SEQUENCE_TIMEOUT};
+use crate::tap::{TapPattern, TapRecognizer};
+use crate::trigger::{TriggerTimer, DEFAULT_TAP_LIMIT};
+use crate::window::FocusedWindow;
+use global_hotkey::{
+    hotkey::HotKey,
+    GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState,
+};
+use rdev::{EventType, Key};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Hotkeys are namespaced per client, so two clients may use the same id.
+type BindingKey = (ClientId, String);
+
+/// Takes and lets go of grabs with the OS. Implemented by global-hotkey's
+/// manager, and in tests by a stand-in whose grabs can be made to fail.
+pub(crate) trait Grabber {
+    fn register(&self, hotkey: HotKey) -> global_hotkey::Result<()>;
+    fn unregister(&self, hotkey: HotKey) -> global_hotkey::Result<()>;
+}
+
+impl Grabber for GlobalHotKeyManager {
+    fn register(&self, hotkey: HotKey) -> global_hotkey::Result<()> {
+        GlobalHotKeyManager::register(self, hotkey)
+    }
+
+    fn unregister(&self, hotkey: HotKey) -> global_hotkey::Result<()> {
+        GlobalHotKeyManager::unregister(self, hotkey)
+    }
+}
+
+/// How a registration is recognized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Gesture {
+    /// Grabbed with the OS; the registration's trigger decides when it fires.
+    Grab(HotKey),
+    /// Recognized from the raw key stream: modifier-only hotkeys and
+    /// multi-taps. A multi-tap on a combination also grabs it while live, so
+    /// that the taps do not reach the focused application; modifiers alone
+    /// cannot be grabbed.
+    Taps(TapPattern, Option<HotKey>),
+    /// Several combinations pressed one after the other. Only the first is
+    /// grabbed for good; the others only while the sequence is pending.
+    Sequence(Vec<HotKey>),
+}
+
+impl Gesture {
+    fn parse(binding: &RegisterCommand, layout: Option<&Layout>) -> Result<Self, String> {
+        let steps = hotkey::parse(&binding.shortcut).map_err(|e| e.to_string())?;
+        if steps.len() > 1 {
+            if binding.taps.is_some() || binding.trigger != Trigger::Press {
+                return Err("sequences cannot be combined with taps or trigger".to_string());
+            }
+            let chords = steps.iter().map(|step| step.grabbed(layout)).collect::<Result<Vec<_>, _>>()?;
+            // The parser makes sure every step has a key
+            return Ok(Gesture::Sequence(chords.iter().filter_map(|chord| chord.hotkey()).collect()));
+        }
+        let step = steps[0];
+
+        let Some(taps) = binding.taps else {
+            if binding.within_ms.is_some() {
+                return Err("within_ms only applies together with taps".to_string());
+            }
+            if let Some(hotkey) = step.grabbed(layout)?.hotkey() {
+                return Ok(Gesture::Grab(hotkey));
+            }
+            // Modifiers alone cannot be grabbed, so they fire on a clean tap instead
+            if binding.trigger != Trigger::Press {
+                return Err("modifier-only hotkeys fire when released and cannot take a trigger".to_string());
+            }
+            return Ok(Gesture::Taps(TapPattern { chord: step.typed(layout)?, taps: 1, within: DEFAULT_TAP_LIMIT }, None));
+        };
+        if taps < 2 {
+            return Err(format!("taps must be at least 2, got {}", taps));
+        }
+        if binding.within_ms == Some(0) {
+            return Err("within_ms must be positive".to_string());
+        }
+        if binding.trigger != Trigger::Press {
+            return Err("taps cannot be combined with trigger".to_string());
+        }
+
+        let within = binding.within_ms.map_or(DEFAULT_TAP_LIMIT, Duration::from_millis);
+        let pattern = TapPattern { chord: step.typed(layout)?, taps, within };
+        Ok(Gesture::Taps(pattern, step.grabbed(layout)?.hotkey()))
+    }
+
+    fn normalized(&self) -> String {
+        match self {
+            Gesture::Grab(hotkey) => hotkey.into_string(),
+            Gesture::Taps(pattern, _) => pattern.chord.to_string(),
+            Gesture::Sequence(steps) => steps_to_string(steps),
+        }
+    }
+}
+
+/// A hotkey id bound on behalf of a client, along with what `list_hotkeys` reports about it.
+struct Registration {
+    shortcut: String,
+    gesture: Gesture,
+    on_conflict: ConflictPolicy,
+    /// Which of `hotkey_pressed`/`hotkey_released` the client wants.
+    events: Vec<HotkeyEventKind>,
+    trigger: Trigger,
+    /// Whether the trigger fired during the current press, which is what
+    /// makes the release worth reporting.
+    active: bool,
+    registered_at: SystemTime,
+    fire_count: u64,
+    /// The binding, if its shortcut names keys by the character they type,
+    /// to resolve it again when the keyboard layout changes.
+    layout_binding: Option<RegisterCommand>,
+    /// The windows or context it is limited to, if any.
+    when: Option<Condition>,
+    /// Cleared by `disable`: the binding stays registered, but is neither
+    /// grabbed nor fired until enabled again.
+    enabled: bool,
+}
+
+/// One grab with the OS, shared by every binding on the same key combination.
+struct Grab {
+    hotkey: HotKey,
+    /// The X keycode global-hotkey grabbed. After a layout change, `hotkey`
+    /// may stand for another key.
+    keycode: Option<u8>,
+    /// Whether it is held with the OS. A grab is let go while none of its
+    /// bindings is live: all are disabled, paused, or out of their `when`.
+    armed: bool,
+    bindings: Vec<BindingKey>,
+    /// Sequences that start with this combination.
+    sequences: Vec<BindingKey>,
+    /// Multi-taps of this combination. They are recognized from the raw key
+    /// stream; the grab only keeps the taps from the focused application.
+    taps: Vec<BindingKey>,
+    timer: TriggerTimer,
+}
+
+impl Grab {
+    /// Every binding that needs the combination grabbed.
+    fn holders(&self) -> impl Iterator<Item = &BindingKey> {
+        self.bindings.iter().chain(&self.sequences).chain(&self.taps)
+    }
+
+    /// Whether no binding holds it any more, so it only waits to be released.
+    fn is_idle(&self) -> bool {
+        self.holders().next().is_none()
+    }
+}
+
+/// Set by `pause_all`, until the same client calls `resume_all` or goes away.
+struct Pause {
+    client: ClientId,
+    /// The one hotkey of the pausing client that stays live, so the user can
+    /// resume.
+    except: Option<String>,
+}
+
+/// What an active layer needs to undo itself when popped.
+struct ActiveLayer {
+    /// The ids it registered.
+    ids: Vec<String>,
+    /// The client's hotkeys it shadows, to be bound again once it is popped.
+    shadowed: Vec<(String, Registration)>,
+}
+
+/// Owns the global hotkey manager together with the bookkeeping needed to map
+/// global-hotkey's numeric ids back to the clients and string ids that registered them.
+pub(crate) struct HotkeyRegistry<G = GlobalHotKeyManager> {
+    manager: G,
+    clients: Arc<Clients>,
+    registered_hotkeys: HashMap<BindingKey, Registration>,
+    /// Grabbed combinations, keyed by global-hotkey's numeric id.
+    grabs: HashMap<u32, Grab>,
+    /// Modifier-only and multi-tap hotkeys, fed from the raw key stream.
+    taps: TapRecognizer<BindingKey>,
+    sequences: SequenceMatcher<BindingKey, HotKey>,
+    /// Combinations grabbed only while a sequence waits for them, keyed by
+    /// global-hotkey's numeric id.
+    captured: HashMap<u32, HotKey>,
+    keymaps: HashMap<ClientId, Keymap<ActiveLayer>>,
+    /// The keyboard, while a client has it captured.
+    capture: Option<Capture>,
+    /// While set, grabs left without bindings are kept until
+    /// `release_idle_grabs`, so a layer change that binds the same
+    /// combination again does not release it in between.
+    deferring: bool,
+    /// The X keyboard mapping, where there is one.
+    layout: Option<Layout>,
+    /// The window with the input focus, where it can be followed.
+    window: Option<FocusedWindow>,
+    /// What each client has set with `set_context`.
+    contexts: HashMap<ClientId, Context>,
+    paused: Option<Pause>,
+}
+
+impl<G: Grabber> HotkeyRegistry<G> {
+    pub(crate) fn new(manager: G, clients: Arc<Clients>, layout: Option<Layout>, window: Option<FocusedWindow>) -> Self {
+        Self {
+            manager,
+            clients,
+            registered_hotkeys: HashMap::new(),
+            grabs: HashMap::new(),
+            taps: TapRecognizer::default(),
+            sequences: SequenceMatcher::default(),
+            captured: HashMap::new(),
+            keymaps: HashMap::new(),
+            capture: None,
+            deferring: false,
+            layout,
+            window,
+            contexts: HashMap::new(),
+            paused: None,
+        }
+    }
+
+    pub(crate) fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    /// Binds `binding.id` to its shortcut for `client`. An id that is already
+    /// registered is replaced; if the new shortcut cannot be bound, the old one
+    /// stays in place. The client's own hotkeys are kept apart from its
+    /// layers': an id that an active layer binds or shadows is replaced
+    /// beneath the layer, and bound once the layer is popped.
+    pub(crate) fn register(&mut self, client: ClientId, binding: &RegisterCommand, context: &'static str) -> Result<(), String> {
+        let key = (client, binding.id.clone());
+        if self.shadowing_layer(&key).is_none() {
+            return self.register_in_effect(client, binding, context);
+        }
+        let registration = self.parse_registration(client, binding, context)?;
+        eprintln!("[system-agent] Registered hotkey for client {} beneath its layers: {} -> {}", client, binding.id, binding.shortcut);
+        self.replace_shadowed(&key, Some(registration));
+        Ok(())
+    }
+
+    /// Binds `binding.id` in effect, replacing whatever is bound under that
+    /// id, the client's own hotkey or a layer's.
+    fn register_in_effect(&mut self, client: ClientId, binding: &RegisterCommand, context: &'static str) -> Result<(), String> {
+        let registration = self.parse_registration(client, binding, context)?;
+        let key = (client, binding.id.clone());
+        let previous = self.take(&key);
+        if let Err(e) = self.bind(key.clone(), registration, context) {
+            if let Some(previous) = previous {
+                self.restore(key, previous);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Parses a binding into a fresh registration, reporting parse errors to
+    /// the client.
+    fn parse_registration(&self, client: ClientId, binding: &RegisterCommand, context: &'static str) -> Result<Registration, String> {
+        let (gesture, when) = match parse_binding(binding, self.layout.as_ref()) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let msg = format!("Failed to parse hotkey {}: {}", binding.shortcut, e);
+                eprintln!("[system-agent] {}", msg);
+                let parse_context = if context == "hotkey_register_batch" { "hotkey_parse_batch" } else { "hotkey_parse" };
+                self.clients.send(client, &Event::Error { 
+                    message: msg.clone(), 
+                    context: parse_context 
+                });
+                return Err(msg);
+            }
+        };
+        Ok(Registration {
+            shortcut: binding.shortcut.clone(),
+            gesture,
+            on_conflict: binding.on_conflict,
+            events: binding.events.clone(),
+            trigger: binding.trigger,
+            active: false,
+            registered_at: SystemTime::now(),
+            fire_count: 0,
+            layout_binding: types_chars(&binding.shortcut).then(|| binding.clone()),
+            when,
+            enabled: true,
+        })
+    }
+
+    /// Adds a binding, grabbing its combination unless another binding already
+    /// holds it and both agreed to share it. Bindings limited to different
+    /// windows never get in each other's way.
+    fn bind(&mut self, key: BindingKey, registration: Registration, context: &'static str) -> Result<(), String> {
+        let (client, id) = &key;
+        let same_scope = |holder: &Registration| holder.when == registration.when;
+
+        // Sequences where one is a prefix of the other could never tell which is meant
+        if let Gesture::Sequence(steps) = &registration.gesture {
+            let overlapping = self.registered_hotkeys.iter().find(|(_, holder)| {
+                same_scope(holder)
+                    && matches!(&holder.gesture, Gesture::Sequence(other) if other != steps && (other.starts_with(steps) || steps.starts_with(other)))
+            });
+            if let Some((holder, _)) = overlapping {
+                return Err(self.conflict(&key, &registration.shortcut, holder, "overlaps the sequence"));
+            }
+        }
+
+        // Bindings that would fire on the very same gesture. Different triggers
+        // (a tap and a hold, say) on one combination are different gestures.
+        let rivals: Vec<&BindingKey> = match &registration.gesture {
+            Gesture::Grab(hotkey) => self.grabs.get(&hotkey.id()).map_or_else(Vec::new, |grab| {
+                grab.bindings
+                    .iter()
+                    .filter(|holder| {
+                        let holder = &self.registered_hotkeys[*holder];
+                        holder.trigger == registration.trigger && same_scope(holder)
+                    })
+                    .collect()
+            }),
+            Gesture::Taps(pattern, _) => self.registered_hotkeys
+                .iter()
+                .filter(|(_, holder)| {
+                    same_scope(holder) && matches!(holder.gesture, Gesture::Taps(other, _) if other.chord == pattern.chord && other.taps == pattern.taps)
+                })
+                .map(|(holder, _)| holder)
+                .collect(),
+            Gesture::Sequence(_) => self.registered_hotkeys
+                .iter()
+                .filter(|(_, holder)| holder.gesture == registration.gesture && same_scope(holder))
+                .map(|(holder, _)| holder)
+                .collect(),
+        };
+        let conflicting = rivals.into_iter().find(|holder| {
+            registration.on_conflict == ConflictPolicy::Reject
+                || self.registered_hotkeys[*holder].on_conflict == ConflictPolicy::Reject
+        });
+        if let Some(holder) = conflicting {
+            return Err(self.conflict(&key, &registration.shortcut, holder, "is already registered as"));
+        }
+
+        let live = self.is_live(&key, &registration);
+        let grabbed = match &registration.gesture {
+            Gesture::Taps(pattern, hotkey) => {
+                if let Some(hotkey) = hotkey {
+                    let grab = self.grab(*hotkey, &key, &registration.shortcut, live, context)?;
+                    grab.taps.push(key.clone());
+                }
+                eprintln!("[system-agent] Registered tap hotkey for client {}: {} -> {} x{}", client, id, registration.shortcut, pattern.taps);
+                self.taps.add(key.clone(), *pattern);
+                *hotkey
+            }
+            Gesture::Grab(hotkey) => {
+                let grab = self.grab(*hotkey, &key, &registration.shortcut, live, context)?;
+                grab.bindings.push(key.clone());
+                Some(*hotkey)
+            }
+            Gesture::Sequence(steps) => {
+                let grab = self.grab(steps[0], &key, &registration.shortcut, live, context)?;
+                grab.sequences.push(key.clone());
+                self.sequences.add(key.clone(), steps.clone());
+                Some(steps[0])
+            }
+        };
+
+        self.registered_hotkeys.insert(key, registration);
+        if let Some(hotkey) = grabbed {
+            // A combination grabbed for a pending sequence may not be wanted
+            // here; failures are reported to the holders
+            let _ = self.sync_grab(hotkey.id(), context);
+        }
+        Ok(())
+    }
+
+    /// Whether a binding of `client` limited to `when` applies in the focused
+    /// window and the client's context.
+    fn applies(&self, client: ClientId, when: Option<&Condition>) -> bool {
+        let empty = Context::new();
+        let context = self.contexts.get(&client).unwrap_or(&empty);
+        when.is_none_or(|condition| condition.holds(self.window.as_ref(), context))
+    }
+
+    /// Whether a binding may fire, and so needs its combination grabbed: it is
+    /// enabled, not paused, and its `when` holds.
+    fn is_live(&self, key: &BindingKey, registration: &Registration) -> bool {
+        registration.enabled
+            && self.paused.as_ref().is_none_or(|pause| pause.client == key.0 && pause.except.as_ref() == Some(&key.1))
+            && self.applies(key.0, registration.when.as_ref())
+    }
+
+    /// Whether `key` is registered and live.
+    fn is_live_key(&self, key: &BindingKey) -> bool {
+        self.registered_hotkeys.get(key).is_some_and(|registration| self.is_live(key, registration))
+    }
+
+    /// Reports that `key` cannot be bound because of `holder`.
+    fn conflict(&self, key: &BindingKey, shortcut: &str, holder: &BindingKey, relation: &str) -> String {
+        let (client, id) = key;
+        let (holder_client, holder_id) = holder;
+        let owner = if holder_client == client { String::new() } else { format!(" of client {}", holder_client) };
+        let msg = format!(
+            "Hotkey conflict: {} for '{}' {} '{}'{}",
+            shortcut, id, relation, holder_id, owner
+        );
+        eprintln!("[system-agent] {}", msg);
+        self.clients.send(*client, &Event::Error { 
+            message: msg.clone(), 
+            context: "conflict" 
+        });
+        msg
+    }
+
+    /// The grab of `hotkey`, for a binding that is `live` in the focused
+    /// window or not. It is taken with the OS now if the binding is live and no
+    /// other binding already holds it.
+    fn grab(&mut self, hotkey: HotKey, key: &BindingKey, shortcut: &str, live: bool, context: &'static str) -> Result<&mut Grab, String> {
+        let (client, id) = key;
+        let hotkey_id = hotkey.id();
+        let armed = if let Some(grab) = self.grabs.get(&hotkey_id) {
+            if grab.is_idle() {
+                eprintln!("[system-agent] Keeping global hotkey for client {}: {} -> {} (ID: {})", client, id, shortcut, hotkey_id);
+            } else {
+                eprintln!("[system-agent] Sharing global hotkey for client {}: {} -> {} (ID: {})", client, id, shortcut, hotkey_id);
+            }
+            grab.armed
+        } else if self.captured.remove(&hotkey_id).is_some() {
+            // Already grabbed for a pending sequence; keep it
+            eprintln!("[system-agent] Keeping captured hotkey for client {}: {} -> {} (ID: {})", client, id, shortcut, hotkey_id);
+            true
+        } else {
+            false
+        };
+        if !armed && !live {
+            eprintln!("[system-agent] Registered global hotkey for client {}: {} -> {}, not grabbed while inactive (ID: {})", client, id, shortcut, hotkey_id);
+        } else if !armed {
+            if let Err(e) = self.manager.register(hotkey) {
+                let msg = format!("Failed to register global hotkey {}: {}", shortcut, e);
+                eprintln!("[system-agent] {}", msg);
+                self.clients.send(*client, &Event::Error { 
+                    message: msg.clone(), 
+                    context 
+                });
+                return Err(msg);
+            }
+
+            eprintln!("[system-agent] Successfully registered global hotkey for client {}: {} -> {} (ID: {})", client, id, shortcut, hotkey_id);
+        }
+        let keycode = self.layout.as_ref().and_then(|layout| layout.grabbed_keycode(hotkey.key));
+        let grab = self.grabs.entry(hotkey_id).or_insert_with(|| Grab {
+            hotkey,
+            keycode,
+            armed: false,
+            bindings: Vec::new(),
+            sequences: Vec::new(),
+            taps: Vec::new(),
+            timer: TriggerTimer::default(),
+        });
+        if live && !armed {
+            grab.keycode = keycode;
+        }
+        grab.armed |= armed || live;
+        Ok(grab)
+    }
+
+    /// Removes a binding, releasing the grab once no other binding shares it.
+    /// Returns the binding, and whether releasing the grab failed.
+    fn release(&mut self, key: &BindingKey) -> Option<(Registration, Result<(), String>)> {
+        let registration = self.registered_hotkeys.remove(key)?;
+        let (client, id) = key;
+        let hotkey = match &registration.gesture {
+            Gesture::Grab(hotkey) => *hotkey,
+            Gesture::Sequence(steps) => {
+                self.sequences.remove(key);
+                steps[0]
+            }
+            Gesture::Taps(_, hotkey) => {
+                self.taps.remove(key);
+                eprintln!("[system-agent] Unregistered tap hotkey for client {}: {}", client, id);
+                let Some(hotkey) = hotkey else { return Some((registration, Ok(()))) };
+                *hotkey
+            }
+        };
+        let hotkey_id = hotkey.id();
+
+        let result = match self.grabs.get_mut(&hotkey_id) {
+            None => Ok(()),
+            Some(grab) => {
+                grab.bindings.retain(|holder| holder != key);
+                grab.sequences.retain(|holder| holder != key);
+                grab.taps.retain(|holder| holder != key);
+                if !grab.is_idle() {
+                    eprintln!("[system-agent] Released shared hotkey for client {}: {} (ID: {})", client, id, hotkey_id);
+                    Ok(())
+                } else if self.deferring {
+                    // Left to release_idle_grabs, in case it is bound again right away
+                    Ok(())
+                } else if !grab.armed {
+                    self.grabs.remove(&hotkey_id);
+                    eprintln!("[system-agent] Unregistered global hotkey for client {}, not grabbed while inactive: {} (ID: {})", client, id, hotkey_id);
+                    Ok(())
+                } else {
+                    let keycode = grab.keycode;
+                    self.grabs.remove(&hotkey_id);
+                    match self.manager.unregister(self.release_target(hotkey, keycode)) {
+                        Ok(()) => {
+                            eprintln!("[system-agent] Successfully unregistered global hotkey for client {}: {} (ID: {})", client, id, hotkey_id);
+                            Ok(())
+                        }
+                        Err(e) => {
+                            let msg = format!("Failed to unregister global hotkey {}: {}", id, e);
+                            eprintln!("[system-agent] {}", msg);
+                            Err(msg)
+                        }
+                    }
+                }
+            }
+        };
+        if self.grabs.contains_key(&hotkey_id) {
+            // The bindings left may all be inactive
+            let _ = self.sync_grab(hotkey_id, "hotkey_unregister");
+        }
+        if matches!(registration.gesture, Gesture::Sequence(_)) {
+            self.sync_captures();
+        }
+        Some((registration, result))
+    }
+
+    /// Releases a binding while keeping its bookkeeping, so that `restore` can
+    /// put it back exactly as it was.
+    fn take(&mut self, key: &BindingKey) -> Option<Registration> {
+        self.release(key).map(|(registration, _)| registration)
+    }
+
+    fn restore(&mut self, key: BindingKey, registration: Registration) {
+        eprintln!("[system-agent] Restoring hotkey for client {}: {}", key.0, key.1);
+        // bind() reports its own failures to the client
+        let _ = self.bind(key, registration, "hotkey_rollback");
+    }
+
+    /// Registers every hotkey of the batch or none of them. Shortcuts are all
+    /// parsed before anything is grabbed; if a grab then fails, the hotkeys
+    /// grabbed so far are released and whatever they replaced is restored.
+    pub(crate) fn register_batch_atomic(&mut self, client: ClientId, hotkeys: Vec<RegisterCommand>) -> Vec<HotkeyFailure> {
+        let mut failures = Vec::new();
+        for binding in &hotkeys {
+            if let Err(e) = parse_binding(binding, self.layout.as_ref()) {
+                let msg = format!("Failed to parse hotkey {}: {}", binding.shortcut, e);
+                eprintln!("[system-agent] {}", msg);
+                self.clients.send(client, &Event::Error { 
+                    message: msg.clone(), 
+                    context: "hotkey_parse_batch" 
+                });
+                failures.push(HotkeyFailure { id: binding.id.clone(), shortcut: binding.shortcut.clone(), error: msg });
+            }
+        }
+        if !failures.is_empty() {
+            return failures;
+        }
+
+        // Every hotkey bound so far, with the registration it replaced
+        let mut journal: Vec<(BindingKey, Option<Registration>)> = Vec::new();
+        for binding in hotkeys {
+            let key = (client, binding.id.clone());
+            if self.shadowing_layer(&key).is_some() {
+                // Beneath a layer nothing is grabbed, and parsing succeeded above
+                if let Ok(registration) = self.parse_registration(client, &binding, "hotkey_register_batch") {
+                    let previous = self.replace_shadowed(&key, Some(registration));
+                    journal.push((key, previous));
+                }
+                continue;
+            }
+            let previous = self.take(&key);
+            match self.register_in_effect(client, &binding, "hotkey_register_batch") {
+                Ok(()) => journal.push((key, previous)),
+                Err(error) => {
+                    if let Some(previous) = previous {
+                        self.restore(key, previous);
+                    }
+                    failures.push(HotkeyFailure { id: binding.id, shortcut: binding.shortcut, error });
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            eprintln!("[system-agent] Rolling back register_batch for client {} ({} failures)", client, failures.len());
+            for (key, previous) in journal.into_iter().rev() {
+                if self.shadowing_layer(&key).is_some() {
+                    self.replace_shadowed(&key, previous);
+                    continue;
+                }
+                // Already logged by release; the failures that matter are reported above
+                self.take(&key);
+                if let Some(previous) = previous {
+                    self.restore(key, previous);
+                }
+            }
+        }
+        failures
+    }
+
+    /// Unregisters one of `client`'s own hotkeys. One that an active layer
+    /// shadows is dropped so that popping the layer does not bring it back;
+    /// the layers' hotkeys go when they are popped.
+    pub(crate) fn unregister(&mut self, client: ClientId, id: &str) -> Result<(), String> {
+        let key = (client, id.to_string());
+        if self.shadowing_layer(&key).is_some() {
+            if self.replace_shadowed(&key, None).is_some() {
+                eprintln!("[system-agent] Unregistered shadowed hotkey for client {}: {}", client, id);
+                return Ok(());
+            }
+            let msg = format!("Hotkey {} belongs to an active layer and is unregistered when the layer is popped", id);
+            eprintln!("[system-agent] Warning: {}", msg);
+            return Err(msg);
+        }
+        let Some((_, result)) = self.release(&key) else {
+            let msg = format!("Attempted to unregister unknown hotkey: {}", id);
+            eprintln!("[system-agent] Warning: {}", msg);
+            return Err(msg);
+        };
+
+        if let Err(msg) = &result {
+            self.clients.send(client, &Event::Error { 
+                message: msg.clone(), 
+                context: "hotkey_unregister" 
+            });
+        }
+        result
+    }
+
+    /// Unregisters every hotkey of `client`'s own, including those its layers
+    /// shadow, leaving its active layers and other clients untouched.
+    pub(crate) fn unregister_all(&mut self, client: ClientId) -> Result<(), String> {
+        let mut failures = Vec::new();
+        let (layer_ids, shadowed) = self.layered_ids(client);
+        let owned: Vec<BindingKey> = self.registered_hotkeys
+            .keys()
+            .filter(|(owner, id)| *owner == client && !layer_ids.contains(id))
+            .cloned()
+            .collect();
+
+        // Unregister the client's hotkeys from the manager
+        for key in owned {
+            if let Some((_, Err(msg))) = self.release(&key) {
+                self.clients.send(client, &Event::Error { 
+                    message: msg.clone(), 
+                    context: "hotkey_unregister_all" 
+                });
+                failures.push(msg);
+            }
+        }
+
+        // Nothing is left for the active layers to put back
+        for id in shadowed {
+            self.replace_shadowed(&(client, id), None);
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("; "))
+        }
+    }
+
+    /// Defines a layer for `client`, checking its shortcuts up front so that
+    /// pushing it can only fail on conflicts.
+    pub(crate) fn define_layer(&mut self, client: ClientId, name: &str, hotkeys: Vec<RegisterCommand>) -> Result<(), String> {
+        for binding in &hotkeys {
+            if let Err(e) = parse_binding(binding, self.layout.as_ref()) {
+                let msg = format!("Failed to parse hotkey {} of layer {}: {}", binding.shortcut, name, e);
+                eprintln!("[system-agent] {}", msg);
+                self.clients.send(client, &Event::Error { 
+                    message: msg.clone(), 
+                    context: "hotkey_parse" 
+                });
+                return Err(msg);
+            }
+        }
+        eprintln!("[system-agent] Defined layer {} for client {} ({} hotkeys)", name, client, hotkeys.len());
+        self.keymaps.entry(client).or_default().define(name, hotkeys)
+    }
+
+    /// Binds a layer's hotkeys on top of `client`'s others. Each one shadows
+    /// the client's hotkeys with the same id or gesture, which are released
+    /// until the layer is popped; a combination the layer binds again stays
+    /// grabbed throughout. Returns the hotkeys that could not be bound.
+    pub(crate) fn push_layer(&mut self, client: ClientId, name: &str) -> Result<Vec<HotkeyFailure>, String> {
+        let hotkeys = self.keymaps.entry(client).or_default().pushable(name)?.to_vec();
+        let mut layer = ActiveLayer { ids: Vec::new(), shadowed: Vec::new() };
+        let mut failures = Vec::new();
+
+        self.deferring = true;
+        for binding in hotkeys {
+            // Checked when the layer was defined
+            let gesture = Gesture::parse(&binding, self.layout.as_ref()).ok();
+            let shadowed_keys: Vec<BindingKey> = self.registered_hotkeys
+                .iter()
+                .filter(|((owner, id), registration)| {
+                    *owner == client
+                        && !layer.ids.contains(id)
+                        && (*id == binding.id || gesture.as_ref() == Some(&registration.gesture))
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            let shadowed: Vec<(BindingKey, Registration)> = shadowed_keys
+                .into_iter()
+                .filter_map(|key| self.take(&key).map(|registration| (key, registration)))
+                .collect();
+
+            match self.register_in_effect(client, &binding, "layer_push") {
+                Ok(()) => {
+                    layer.ids.push(binding.id);
+                    layer.shadowed.extend(shadowed.into_iter().map(|((_, id), registration)| (id, registration)));
+                }
+                Err(error) => {
+                    for (key, registration) in shadowed {
+                        self.restore(key, registration);
+                    }
+                    failures.push(HotkeyFailure { id: binding.id, shortcut: binding.shortcut, error });
+                }
+            }
+        }
+        self.release_idle_grabs();
+
+        eprintln!(
+            "[system-agent] Pushed layer {} for client {} ({} bound, {} shadowed, {} failed)",
+            name, client, layer.ids.len(), layer.shadowed.len(), failures.len()
+        );
+        self.keymaps.entry(client).or_default().push(name, layer);
+        Ok(failures)
+    }
+
+    /// Releases the top layer's hotkeys and binds again what it shadowed.
+    pub(crate) fn pop_layer(&mut self, client: ClientId, name: Option<&str>) -> Result<(), String> {
+        let (name, layer) = self.keymaps.entry(client).or_default().pop(name)?;
+
+        self.deferring = true;
+        for id in layer.ids.into_iter().rev() {
+            self.take(&(client, id));
+        }
+        for (id, mut registration) in layer.shadowed.into_iter().rev() {
+            let key = (client, id);
+            // Registered again while it was shadowed
+            if self.registered_hotkeys.contains_key(&key) {
+                continue;
+            }
+            registration.active = false;
+            // bind() reports its own failures to the client
+            let _ = self.bind(key, registration, "layer_pop");
+        }
+        self.release_idle_grabs();
+
+        eprintln!("[system-agent] Popped layer {} for client {}", name, client);
+        Ok(())
+    }
+
+    /// The names of `client`'s active layers, bottom first.
+    pub(crate) fn layers(&self, client: ClientId) -> Vec<String> {
+        self.keymaps.get(&client).map_or_else(Vec::new, Keymap::active)
+    }
+
+    /// Drops what `client` holds besides its own hotkeys, once it is gone:
+    /// its layers and their hotkeys, its keyboard capture and its pause.
+    pub(crate) fn forget(&mut self, client: ClientId) {
+        let layered: Vec<BindingKey> = self.registered_hotkeys.keys().filter(|(owner, _)| *owner == client).cloned().collect();
+        for key in layered {
+            // Already logged by release, and there is nobody left to tell
+            self.take(&key);
+        }
+        self.keymaps.remove(&client);
+        self.contexts.remove(&client);
+        if self.capture.as_ref().is_some_and(|capture| capture.client == client) {
+            eprintln!("[system-agent] Releasing keyboard captured by disconnected client {}", client);
+            self.capture = None;
+        }
+        if self.paused.as_ref().is_some_and(|pause| pause.client == client) {
+            eprintln!("[system-agent] Resuming hotkeys paused by disconnected client {}", client);
+            self.paused = None;
+            // Failures are reported to the holders
+            let _ = self.sync_grabs("resume_all");
+        }
+    }
+
+    /// The active layer of `key`'s client that keeps the client's own hotkey
+    /// of that id out of effect: the lowest one that shadows it or binds the
+    /// id itself. None if no layer covers the id.
+    fn shadowing_layer(&mut self, key: &BindingKey) -> Option<&mut ActiveLayer> {
+        let (client, id) = key;
+        self.keymaps
+            .get_mut(client)?
+            .active_mut()
+            .find(|layer| layer.ids.contains(id) || layer.shadowed.iter().any(|(shadowed, _)| shadowed == id))
+    }
+
+    /// Replaces the client's own hotkey of `key` beneath the layer that covers
+    /// it, to be bound once that layer is popped; `None` drops it. Returns
+    /// what it replaced.
+    fn replace_shadowed(&mut self, key: &BindingKey, registration: Option<Registration>) -> Option<Registration> {
+        let layer = self.shadowing_layer(key)?;
+        let previous = layer.shadowed
+            .iter()
+            .position(|(id, _)| *id == key.1)
+            .map(|index| layer.shadowed.remove(index).1);
+        if let Some(registration) = registration {
+            layer.shadowed.push((key.1.clone(), registration));
+        }
+        previous
+    }
+
+    /// The ids `client`'s active layers bind, and the ids of the client's own
+    /// hotkeys they shadow, as opposed to the layers' own shadowed by higher
+    /// layers.
+    fn layered_ids(&self, client: ClientId) -> (HashSet<String>, Vec<String>) {
+        let mut layer_ids = HashSet::new();
+        let mut shadowed = Vec::new();
+        for layer in self.keymaps.get(&client).into_iter().flat_map(Keymap::active_states) {
+            shadowed.extend(layer.shadowed.iter().map(|(id, _)| id).filter(|id| !layer_ids.contains(*id)).cloned());
+            layer_ids.extend(layer.ids.iter().cloned());
+        }
+        (layer_ids, shadowed)
+    }
+
+    /// Grabs the keyboard for `client`, or restarts the timeout of its capture.
+    pub(crate) fn begin_capture(&mut self, client: ClientId, timeout: Option<Duration>) -> Result<(), String> {
+        let timeout = match timeout {
+            Some(timeout) if timeout.is_zero() => return Err("timeout_ms must be positive".to_string()),
+            Some(timeout) => timeout,
+            None => DEFAULT_CAPTURE_TIMEOUT,
+        };
+        let now = Instant::now();
+        match &mut self.capture {
+            Some(capture) if capture.client == client => {
+                capture.renew(timeout, now);
+                return Ok(());
+            }
+            Some(capture) => return Err(format!("The keyboard is already captured by client {}", capture.client)),
+            None => {}
+        }
+
+        let capture = Capture::begin(client, timeout, now).inspect_err(|msg| {
+            eprintln!("[system-agent] {}", msg);
+            self.clients.send(client, &Event::Error { 
+                message: msg.clone(), 
+                context: "capture" 
+            });
+        })?;
+        eprintln!("[system-agent] Keyboard captured by client {} ({} ms timeout)", client, timeout.as_millis());
+        // Its next step could not be told apart from a captured key
+        if let Some(cancelled) = self.sequences.cancel() {
+            self.sequence_cancelled(cancelled, SequenceCancelReason::OtherKey);
+        }
+        self.capture = Some(capture);
+        Ok(())
+    }
+
+    pub(crate) fn end_capture(&mut self, client: ClientId) -> Result<(), String> {
+        if self.capture.as_ref().is_none_or(|capture| capture.client != client) {
+            return Err("No keyboard capture is active for this client".to_string());
+        }
+        self.finish_capture(CaptureEndReason::Command);
+        Ok(())
+    }
+
+    /// Releases the keyboard and tells the client that captured it.
+    fn finish_capture(&mut self, reason: CaptureEndReason) {
+        let Some(capture) = self.capture.take() else { return };
+        eprintln!("[system-agent] Keyboard capture of client {} ended ({:?})", capture.client, reason);
+        self.clients.send(capture.client, &Event::CaptureEnded { 
+            reason 
+        });
+    }
+
+    /// Reports a key pressed during a capture to the client that holds it.
+    fn captured_key(&mut self, at: Instant, key: Key, name: Option<String>) {
+        let Some(capture) = &mut self.capture else { return };
+        // Modifiers are reported along with the key they modify
+        if at < capture.since() || keys::modifier(key).is_some() {
+            return;
+        }
+        if key == Key::Escape {
+            self.finish_capture(CaptureEndReason::Escape);
+            return;
+        }
+        capture.key_pressed(at);
+
+        let client = capture.client;
+        let key_name = keys::code(key).map_or_else(|| format!("{:?}", key), |code| code.to_string());
+        // Enter, Tab and the like type control characters
+        let char = name.filter(|name| !name.is_empty() && !name.chars().any(char::is_control));
+        self.clients.send(client, &Event::CapturedKey { 
+            key: key_name, 
+            char, 
+            modifiers: keys::modifier_names(self.taps.held_modifiers()) 
+        });
+    }
+
+    /// Ends a change made while `deferring`, releasing the grabs it left
+    /// without any binding.
+    fn release_idle_grabs(&mut self) {
+        self.deferring = false;
+        let idle: Vec<u32> = self.grabs
+            .iter()
+            .filter(|(_, grab)| grab.is_idle())
+            .map(|(hotkey_id, _)| *hotkey_id)
+            .collect();
+        for hotkey_id in idle {
+            let grab = self.grabs.remove(&hotkey_id).expect("listed above");
+            if !grab.armed {
+                continue;
+            }
+            match self.manager.unregister(self.release_target(grab.hotkey, grab.keycode)) {
+                Ok(()) => eprintln!("[system-agent] Successfully unregistered global hotkey {} (ID: {})", grab.hotkey, hotkey_id),
+                Err(e) => eprintln!("[system-agent] Failed to unregister global hotkey {}: {}", grab.hotkey, e),
+            }
+        }
+        // Steps of a pending sequence may have lost their grab
+        self.sync_captures();
+    }
+
+    /// What to ask global-hotkey to release for a grab of `hotkey` on
+    /// `keycode`. It looks the key up again, so once the layout has moved the
+    /// code's character to another key, a code leading to the grabbed key is
+    /// needed instead.
+    fn release_target(&self, hotkey: HotKey, keycode: Option<u8>) -> HotKey {
+        let (Some(layout), Some(keycode)) = (&self.layout, keycode) else { return hotkey };
+        if layout.grabbed_keycode(hotkey.key) == Some(keycode) {
+            return hotkey;
+        }
+        layout.code_for(keycode).map_or(hotkey, |code| HotKey::new(Some(hotkey.mods), code))
+    }
+
+    /// Follows a change of keyboard layout: `char:` hotkeys move to the key
+    /// that now types their character. Other grabs stay on their keys.
+    pub(crate) fn layout_changed(&mut self, layout: Layout) {
+        eprintln!("[system-agent] Keyboard layout changed");
+        self.layout = Some(layout);
+        let layout = self.layout.as_ref();
+
+        // Shadowed hotkeys are bound again as they are once their layer is popped
+        for keymap in self.keymaps.values_mut() {
+            for active in keymap.active_mut() {
+                for (_, registration) in &mut active.shadowed {
+                    if let Some(Ok(gesture)) = registration.layout_binding.as_ref().map(|binding| Gesture::parse(binding, layout)) {
+                        registration.gesture = gesture;
+                    }
+                }
+            }
+        }
+
+        let moved: Vec<(BindingKey, RegisterCommand)> = self.registered_hotkeys
+            .iter()
+            .filter_map(|(key, registration)| {
+                let binding = registration.layout_binding.as_ref()?;
+                let gesture = Gesture::parse(binding, layout);
+                (gesture.as_ref() != Ok(&registration.gesture)).then(|| (key.clone(), binding.clone()))
+            })
+            .collect();
+        self.deferring = true;
+        for ((client, id), binding) in moved {
+            let previous = &self.registered_hotkeys[&(client, id.clone())];
+            let (registered_at, fire_count, enabled) = (previous.registered_at, previous.fire_count, previous.enabled);
+            eprintln!("[system-agent] Moving hotkey for client {} to the new layout: {} -> {}", client, id, binding.shortcut);
+            // register() reports failures to the client and leaves the hotkey where it was
+            if self.register_in_effect(client, &binding, "layout_change").is_ok() {
+                let registration = self.registered_hotkeys.get_mut(&(client, id.clone())).expect("just registered");
+                registration.registered_at = registered_at;
+                registration.fire_count = fire_count;
+                if !enabled {
+                    // Failures are reported to the client
+                    let _ = self.set_enabled(client, &id, false);
+                }
+            }
+        }
+        self.release_idle_grabs();
+        self.regrab_moved_keys();
+    }
+
+    /// Grabs `char:` hotkeys again whose code is unchanged by a layout
+    /// change, but now stands for another key.
+    fn regrab_moved_keys(&mut self) {
+        let Some(layout) = &self.layout else { return };
+        let moved: Vec<u32> = self.grabs
+            .iter()
+            .filter(|(_, grab)| {
+                grab.armed
+                    && grab.keycode.is_some()
+                    && layout.grabbed_keycode(grab.hotkey.key) != grab.keycode
+                    && grab.holders().any(|key| self.registered_hotkeys[key].layout_binding.is_some())
+            })
+            .map(|(hotkey_id, _)| *hotkey_id)
+            .collect();
+
+        for hotkey_id in moved {
+            let grab = &self.grabs[&hotkey_id];
+            let (hotkey, keycode) = (grab.hotkey, grab.keycode);
+            let result = self.manager
+                .unregister(self.release_target(hotkey, keycode))
+                .and_then(|()| self.manager.register(hotkey));
+            let grab = self.grabs.get_mut(&hotkey_id).expect("listed above");
+            match result {
+                Ok(()) => {
+                    eprintln!("[system-agent] Moved global hotkey {} to the key that now types it (ID: {})", hotkey, hotkey_id);
+                    grab.keycode = layout.grabbed_keycode(hotkey.key);
+                }
+                Err(e) => {
+                    let msg = format!("Failed to move global hotkey {} to the new keyboard layout: {}", hotkey, e);
+                    eprintln!("[system-agent] {}", msg);
+                    let holders: BTreeSet<ClientId> = grab.holders().map(|(client, _)| *client).collect();
+                    for client in holders {
+                        self.clients.send(client, &Event::Error { 
+                            message: msg.clone(), 
+                            context: "layout_change" 
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Follows the focus to another window, or the focused window to another
+    /// class or title, grabbing the combinations of window-scoped hotkeys only
+    /// while one of their windows is focused.
+    pub(crate) fn focus_changed(&mut self, window: Option<FocusedWindow>) {
+        self.window = window;
+        // Failures are reported to the holders
+        let _ = self.sync_grabs("window_scope");
+    }
+
+    /// Sets or, given no value, removes keys of `client`'s context, grabbing
+    /// or letting go of the combinations whose `when` it changes.
+    pub(crate) fn set_context(&mut self, client: ClientId, values: BTreeMap<String, Option<ContextValue>>) {
+        let context = self.contexts.entry(client).or_default();
+        for (key, value) in values {
+            eprintln!("[system-agent] Context of client {}: {} = {:?}", client, key, value);
+            match value {
+                Some(value) => context.insert(key, value),
+                None => context.remove(&key),
+            };
+        }
+        // Failures are reported to the holders
+        let _ = self.sync_grabs("set_context");
+    }
+
+    /// Lets go of every grab but the one of `except`, one of `client`'s
+    /// hotkeys, keeping the registrations to be grabbed again by `resume_all`.
+    /// Nothing else fires meanwhile. Only the client that paused may pause
+    /// again, to change `except`.
+    pub(crate) fn pause_all(&mut self, client: ClientId, except: Option<&str>) -> Result<(), String> {
+        if let Some(pause) = self.paused.as_ref().filter(|pause| pause.client != client) {
+            return Err(format!("Hotkeys are already paused by client {}", pause.client));
+        }
+        if let Some(id) = except.filter(|id| !self.registered_hotkeys.contains_key(&(client, id.to_string()))) {
+            return Err(format!("Unknown hotkey: {}", id));
+        }
+        eprintln!("[system-agent] Pausing hotkeys for client {}{}", client, except.map_or(String::new(), |id| format!(" except {}", id)));
+        self.paused = Some(Pause { client, except: except.map(str::to_string) });
+        self.sync_grabs("pause_all")
+    }
+
+    /// Grabs again what `pause_all` let go of, except disabled hotkeys. Only
+    /// the client that paused may resume.
+    pub(crate) fn resume_all(&mut self, client: ClientId) -> Result<(), String> {
+        match &self.paused {
+            None => return Ok(()),
+            Some(pause) if pause.client != client => {
+                return Err(format!("Hotkeys were paused by client {}", pause.client));
+            }
+            Some(_) => {}
+        }
+        eprintln!("[system-agent] Resuming hotkeys for client {}", client);
+        self.paused = None;
+        self.sync_grabs("resume_all")
+    }
+
+    /// Enables or disables one of `client`'s hotkeys. A disabled hotkey keeps
+    /// its registration, but is not grabbed and does not fire.
+    pub(crate) fn set_enabled(&mut self, client: ClientId, id: &str, enabled: bool) -> Result<(), String> {
+        let Some(registration) = self.registered_hotkeys.get_mut(&(client, id.to_string())) else {
+            return Err(format!("Unknown hotkey: {}", id));
+        };
+        registration.enabled = enabled;
+        eprintln!("[system-agent] {} hotkey for client {}: {}", if enabled { "Enabled" } else { "Disabled" }, client, id);
+        let hotkey_id = match &registration.gesture {
+            Gesture::Grab(hotkey) => hotkey.id(),
+            Gesture::Sequence(steps) => steps[0].id(),
+            Gesture::Taps(_, Some(hotkey)) => hotkey.id(),
+            Gesture::Taps(_, None) => return Ok(()),
+        };
+        self.sync_grab(hotkey_id, if enabled { "hotkey_enable" } else { "hotkey_disable" })
+    }
+
+    /// Runs `sync_grab` on every grab, returning every failure. The steps of a
+    /// pending sequence are captured again for the sequences still live.
+    fn sync_grabs(&mut self, context: &'static str) -> Result<(), String> {
+        let hotkey_ids: Vec<u32> = self.grabs.keys().copied().collect();
+        let failures: Vec<String> = hotkey_ids
+            .into_iter()
+            .filter_map(|hotkey_id| self.sync_grab(hotkey_id, context).err())
+            .collect();
+        self.sync_captures();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("; "))
+        }
+    }
+
+    /// Grabs a combination once a binding on it is live, and lets go of it
+    /// once none is, reporting failures to the holders with `context`. Grabs
+    /// without bindings are left to `release_idle_grabs`.
+    fn sync_grab(&mut self, hotkey_id: u32, context: &'static str) -> Result<(), String> {
+        let grab = &self.grabs[&hotkey_id];
+        if grab.is_idle() {
+            return Ok(());
+        }
+        let wanted = grab.holders().any(|key| self.is_live(key, &self.registered_hotkeys[key]));
+        if wanted == grab.armed {
+            return Ok(());
+        }
+        let hotkey = grab.hotkey;
+        let result = if wanted {
+            self.manager.register(hotkey)
+        } else {
+            self.manager.unregister(self.release_target(hotkey, grab.keycode))
+        };
+        let keycode = self.layout.as_ref().and_then(|layout| layout.grabbed_keycode(hotkey.key));
+        let grab = self.grabs.get_mut(&hotkey_id).expect("checked above");
+        match result {
+            Ok(()) if wanted => {
+                eprintln!("[system-agent] Grabbed global hotkey {} as a hotkey on it became active (ID: {})", hotkey, hotkey_id);
+                grab.armed = true;
+                grab.keycode = keycode;
+                Ok(())
+            }
+            Ok(()) => {
+                eprintln!("[system-agent] Let go of global hotkey {} while no hotkey on it is active (ID: {})", hotkey, hotkey_id);
+                grab.armed = false;
+                Ok(())
+            }
+            Err(e) => {
+                let action = if wanted { "grab" } else { "release" };
+                let msg = format!("Failed to {} global hotkey {}: {}", action, hotkey, e);
+                eprintln!("[system-agent] {}", msg);
+                let holders: BTreeSet<ClientId> = grab.holders().map(|(client, _)| *client).collect();
+                for client in holders {
+                    self.clients.send(client, &Event::Error { 
+                        message: msg.clone(), 
+                        context 
+                    });
+                }
+                Err(msg)
+            }
+        }
+    }
+
+    /// Brings `client`'s hotkeys in line with `desired`, touching only what
+    /// differs so that unchanged grabs are never released. Removals and changed
+    /// shortcuts are released before anything new is grabbed, so hotkeys can
+    /// swap shortcuts within one sync. A hotkey whose new shortcut cannot be
+    /// bound gets its old one back.
+    pub(crate) fn sync(&mut self, client: ClientId, desired: Vec<RegisterCommand>) -> Vec<SyncChange> {
+        let mut changes = Vec::new();
+        let mut desired_ids = HashSet::new();
+        let mut to_apply = Vec::new();
+        for binding in desired {
+            if desired_ids.insert(binding.id.clone()) {
+                to_apply.push(binding);
+            } else {
+                changes.push(SyncChange::failed(binding.id, "Duplicate id in sync_hotkeys".to_string()));
+            }
+        }
+
+        // Release what is no longer wanted, or wanted with a different
+        // shortcut. The hotkeys of the client's layers are not in its set;
+        // its own hotkeys that the layers shadow are changed beneath them.
+        let (layer_ids, shadowed) = self.layered_ids(client);
+        let mut owned: Vec<String> = self.registered_hotkeys
+            .keys()
+            .filter(|(owner, id)| *owner == client && !layer_ids.contains(id))
+            .map(|(_, id)| id.clone())
+            .chain(shadowed)
+            .collect();
+        owned.sort();
+        let mut unchanged = HashSet::new();
+        // Changed hotkeys, with the registration released to make way for them
+        let mut replaced: HashMap<String, Option<Registration>> = HashMap::new();
+        for id in owned {
+            let key = (client, id.clone());
+            let wanted = to_apply.iter().find(|binding| binding.id == id);
+            let parsed = wanted.map(|binding| parse_binding(binding, self.layout.as_ref()));
+            let current = self.own_registration_mut(&key).expect("listed above");
+            match (wanted, parsed) {
+                (Some(binding), Some(Ok((gesture, when))))
+                    if binding.trigger == current.trigger
+                        && binding.on_conflict == current.on_conflict
+                        && gesture == current.gesture
+                        && when == current.when =>
+                {
+                    unchanged.insert(id);
+                }
+                (Some(_), _) => {
+                    // Beneath a layer, register() replaces it where it is
+                    let previous = if self.shadowing_layer(&key).is_none() { self.take(&key) } else { None };
+                    replaced.insert(id, previous);
+                }
+                (None, _) => changes.push(match self.unregister(client, &id) {
+                    Ok(()) => SyncChange { id, action: SyncAction::Removed, error: None },
+                    Err(e) => SyncChange::failed(id, e),
+                }),
+            }
+        }
+
+        // Grab what is new or changed, in the order the client listed it
+        for binding in to_apply {
+            if unchanged.contains(&binding.id) {
+                let registration = self.own_registration_mut(&(client, binding.id.clone())).expect("listed above");
+                // Same hotkey, perhaps spelled differently
+                registration.layout_binding = types_chars(&binding.shortcut).then(|| binding.clone());
+                registration.shortcut = binding.shortcut;
+                registration.events = binding.events;
+                changes.push(SyncChange { id: binding.id, action: SyncAction::Unchanged, error: None });
+                continue;
+            }
+            let previous = replaced.remove(&binding.id);
+            let action = if previous.is_some() { SyncAction::Updated } else { SyncAction::Added };
+            changes.push(match self.register(client, &binding, "hotkey_sync") {
+                Ok(()) => SyncChange { id: binding.id, action, error: None },
+                Err(e) => {
+                    if let Some(Some(previous)) = previous {
+                        self.restore((client, binding.id.clone()), previous);
+                    }
+                    SyncChange::failed(binding.id, e)
+                }
+            });
+        }
+        changes
+    }
+
+    /// `client`'s own hotkey of `key`, in effect or beneath one of its layers.
+    fn own_registration_mut(&mut self, key: &BindingKey) -> Option<&mut Registration> {
+        if self.shadowing_layer(key).is_some() {
+            let layer = self.shadowing_layer(key)?;
+            return layer.shadowed.iter_mut().find(|(id, _)| *id == key.1).map(|(_, registration)| registration);
+        }
+        self.registered_hotkeys.get_mut(key)
+    }
+
+    /// Releases every grab, for every client. Used on shutdown.
+    pub(crate) fn clear(&mut self) {
+        eprintln!("[system-agent] Cleaning up registered hotkeys...");
+        self.registered_hotkeys.clear();
+        self.sequences = SequenceMatcher::default();
+        self.taps = TapRecognizer::default();
+        self.capture = None;
+        for (hotkey_id, hotkey) in self.captured.drain() {
+            if let Err(e) = self.manager.unregister(hotkey) {
+                eprintln!("[system-agent] Failed to release captured hotkey {} during cleanup: {}", hotkey_id, e);
+            }
+        }
+        let grabs: Vec<(u32, Grab)> = self.grabs.drain().collect();
+        for (hotkey_id, grab) in grabs.into_iter().filter(|(_, grab)| grab.armed) {
+            if let Err(e) = self.manager.unregister(self.release_target(grab.hotkey, grab.keycode)) {
+                eprintln!("[system-agent] Failed to unregister hotkey {} during cleanup: {}", hotkey_id, e);
+            } else {
+                eprintln!("[system-agent] Cleaned up hotkey ID {} ({} bindings)", hotkey_id, grab.bindings.len());
+            }
+        }
+    }
+
+    /// Routes a grabbed key press or release to the bindings sharing the
+    /// combination whose trigger it completes.
+    pub(crate) fn dispatch(&mut self, event: GlobalHotKeyEvent) {
+        eprintln!("[system-agent] Global hotkey triggered: {:?}", event);
+
+        // Look up the owning clients and original string IDs
+        let hotkey = match (self.grabs.get(&event.id), self.captured.get(&event.id)) {
+            (Some(grab), _) => grab.hotkey,
+            (None, Some(hotkey)) => *hotkey,
+            (None, None) => {
+                // Releases of captured keys may come after the capture ended
+                if event.state == HotKeyState::Pressed {
+                    eprintln!("[system-agent] Warning: Received hotkey event for unknown ID: {}", event.id);
+                }
+                return;
+            }
+        };
+        let now = Instant::now();
+        if event.state == HotKeyState::Pressed && self.advance_sequences(now, hotkey) {
+            // A later step of a sequence goes nowhere else
+            return;
+        }
+        if !self.grabs.contains_key(&event.id) {
+            return;
+        }
+        let triggers = self.triggers(event.id);
+        let grab = self.grabs.get_mut(&event.id).expect("checked above");
+
+        match event.state {
+            HotKeyState::Pressed => {
+                let fired = grab.timer.press(now, &triggers);
+                let bindings = grab.bindings.clone();
+                for key in &bindings {
+                    if let Some(registration) = self.registered_hotkeys.get_mut(key) {
+                        registration.active = false;
+                    }
+                }
+                self.fire(fired.into_iter().map(|index| bindings[index].clone()));
+            }
+            HotKeyState::Released => {
+                // A release without a press was held since before the hotkey was registered
+                let Some((held, fired)) = grab.timer.release(now, &triggers) else { return };
+                let bindings = grab.bindings.clone();
+                self.fire(fired.into_iter().map(|index| bindings[index].clone()));
+
+                let held_ms = held.as_millis() as u64;
+                for key in &bindings {
+                    let Some(registration) = self.registered_hotkeys.get_mut(key) else { continue };
+                    if !std::mem::take(&mut registration.active) || !registration.events.contains(&HotkeyEventKind::Released) {
+                        continue;
+                    }
+                    let (client, original_id) = key;
+                    eprintln!("[system-agent] Sending hotkey_released event to client {} for: {} ({} ms)", client, original_id, held_ms);
+                    self.clients.send(*client, &Event::HotkeyReleased { 
+                        id: original_id.clone(), 
+                        held_ms 
+                    });
+                }
+            }
+        }
+    }
+
+    /// Feeds a grabbed press to the sequences. Returns whether it continued
+    /// or completed one, rather than just starting one or none.
+    fn advance_sequences(&mut self, now: Instant, hotkey: HotKey) -> bool {
+        let (cancelled, progress) = self.sequences.press(now, hotkey);
+        if let Some(cancelled) = cancelled {
+            self.sequence_cancelled(cancelled, SequenceCancelReason::OtherKey);
+        }
+        let consumed = match progress {
+            Progress::None => false,
+            Progress::Pending { prefix, candidates } => {
+                let candidates: Vec<BindingKey> = candidates.into_iter().filter(|key| self.is_live_key(key)).collect();
+                if candidates.is_empty() {
+                    // Only sequences that are disabled, paused or out of their
+                    // `when` start here; the press is the grab's own
+                    self.sequences.cancel();
+                    false
+                } else {
+                    let prefix = steps_to_string(&prefix);
+                    eprintln!("[system-agent] Sequence pending: {}", prefix);
+                    let timeout_ms = SEQUENCE_TIMEOUT.as_millis() as u64;
+                    self.send_per_client(candidates, |ids| Event::SequencePending { 
+                        prefix: prefix.clone(), 
+                        ids, 
+                        timeout_ms 
+                    });
+                    self.sequences.prefix().len() > 1
+                }
+            }
+            Progress::Completed(ids) => {
+                self.fire(ids);
+                true
+            }
+        };
+        self.sync_captures();
+        consumed
+    }
+
+    fn sequence_cancelled(&mut self, cancelled: Cancelled<BindingKey, HotKey>, reason: SequenceCancelReason) {
+        let prefix = steps_to_string(&cancelled.prefix);
+        eprintln!("[system-agent] Sequence cancelled ({:?}): {}", reason, prefix);
+        let candidates: Vec<BindingKey> = cancelled.candidates.into_iter().filter(|key| self.is_live_key(key)).collect();
+        self.send_per_client(candidates, |ids| Event::SequenceCancelled { 
+            prefix: prefix.clone(), 
+            ids, 
+            reason 
+        });
+        self.sync_captures();
+    }
+
+    /// Sends one event to each client owning some of `bindings`, listing its ids.
+    fn send_per_client(&self, bindings: Vec<BindingKey>, event: impl Fn(Vec<String>) -> Event) {
+        let mut ids_by_client: BTreeMap<ClientId, Vec<String>> = BTreeMap::new();
+        for (client, id) in bindings {
+            ids_by_client.entry(client).or_default().push(id);
+        }
+        for (client, ids) in ids_by_client {
+            self.clients.send(client, &event(ids));
+        }
+    }
+
+    /// Grabs the combinations that continue the pending sequence, so they do
+    /// not reach the focused application, and lets go of the ones that no
+    /// longer do.
+    fn sync_captures(&mut self) {
+        let wanted: Vec<HotKey> = self.sequences
+            .next_steps_of(|key| self.is_live_key(key))
+            .into_iter()
+            .filter(|step| !self.grabs.contains_key(&step.id()))
+            .collect();
+
+        let stale: Vec<u32> = self.captured
+            .keys()
+            .filter(|hotkey_id| !wanted.iter().any(|step| step.id() == **hotkey_id))
+            .copied()
+            .collect();
+        for hotkey_id in stale {
+            let hotkey = self.captured.remove(&hotkey_id).expect("listed above");
+            if let Err(e) = self.manager.unregister(hotkey) {
+                eprintln!("[system-agent] Failed to release captured hotkey {}: {}", hotkey, e);
+            }
+        }
+
+        for step in wanted {
+            if self.captured.contains_key(&step.id()) {
+                continue;
+            }
+            match self.manager.register(step) {
+                Ok(()) => {
+                    self.captured.insert(step.id(), step);
+                }
+                Err(e) => eprintln!("[system-agent] Failed to capture {} for a pending sequence: {}", step, e),
+            }
+        }
+    }
+
+    /// Feeds a raw key or mouse button event to the tap recognizer, and
+    /// cancels a pending sequence when a key that does not continue it goes down.
+    /// During a keyboard capture, key presses go to the capture instead.
+    pub(crate) fn key_event(&mut self, at: Instant, event: rdev::Event) {
+        let fired = match event.event_type {
+            EventType::KeyPress(key) => self.taps.key_down(at, key),
+            EventType::KeyRelease(key) => self.taps.key_up(at, key),
+            EventType::ButtonPress(_) => self.taps.button_down(at),
+            _ => return,
+        };
+        if self.capture.is_some() {
+            // The recognizer still tracks which modifiers are held, but nothing fires
+            if let EventType::KeyPress(key) = event.event_type {
+                self.captured_key(at, key, event.name);
+            }
+            return;
+        }
+        self.fire(fired);
+
+        if let EventType::KeyPress(key) = event.event_type {
+            self.check_sequence_key(at, key);
+        }
+    }
+
+    fn check_sequence_key(&mut self, at: Instant, key: Key) {
+        // Events from before the sequence started can still be queued
+        if self.sequences.pending_since().is_none_or(|since| at < since) {
+            return;
+        }
+        // Modifiers are part of the next step rather than a step of their own
+        let Some(code) = keys::code(key) else { return };
+        // Grabs name keys by what they type, the raw stream by where they are
+        let code = self.layout
+            .as_ref()
+            .and_then(|layout| layout.code_for(layout::physical_keycode(code)?))
+            .unwrap_or(code);
+        // The raw stream sees grabbed keys too, in no particular order with the grab
+        let step = HotKey::new(Some(self.taps.held_modifiers()), code);
+        if self.sequences.prefix().last() == Some(&step) || self.sequences.next_steps_of(|key| self.is_live_key(key)).contains(&step) {
+            return;
+        }
+        if let Some(cancelled) = self.sequences.cancel() {
+            self.sequence_cancelled(cancelled, SequenceCancelReason::OtherKey);
+        }
+    }
+
+    /// Fires hold triggers and multi-taps whose time has come, and gives up on
+    /// sequences whose time has run out.
+    pub(crate) fn tick(&mut self, now: Instant) {
+        let hotkey_ids: Vec<u32> = self.grabs.keys().copied().collect();
+        for hotkey_id in hotkey_ids {
+            let triggers = self.triggers(hotkey_id);
+            let grab = self.grabs.get_mut(&hotkey_id).expect("listed above");
+            let fired = grab.timer.tick(now, &triggers);
+            if !fired.is_empty() {
+                let bindings = grab.bindings.clone();
+                self.fire(fired.into_iter().map(|index| bindings[index].clone()));
+            }
+        }
+        let fired = self.taps.tick(now);
+        self.fire(fired);
+        if let Some(cancelled) = self.sequences.tick(now) {
+            self.sequence_cancelled(cancelled, SequenceCancelReason::Timeout);
+        }
+        if self.capture.as_ref().is_some_and(|capture| capture.deadline() <= now) {
+            self.finish_capture(CaptureEndReason::Timeout);
+        }
+    }
+
+    /// When `tick` next has something to do, if a hold trigger, multi-tap,
+    /// sequence or keyboard capture is pending.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.grabs
+            .iter()
+            .filter_map(|(hotkey_id, grab)| grab.timer.next_deadline(&self.triggers(*hotkey_id)))
+            .chain(self.taps.next_deadline())
+            .chain(self.sequences.next_deadline())
+            .chain(self.capture.as_ref().map(Capture::deadline))
+            .min()
+    }
+
+    /// The triggers of a grab's bindings, in binding order.
+    fn triggers(&self, hotkey_id: u32) -> Vec<Trigger> {
+        self.grabs[&hotkey_id]
+            .bindings
+            .iter()
+            .map(|key| self.registered_hotkeys[key].trigger)
+            .collect()
+    }
+
+    /// Activates the `fired` bindings, reporting `hotkey_pressed` to the ones that want it.
+    fn fire(&mut self, fired: impl IntoIterator<Item = BindingKey>) {
+        for key in fired {
+            // Another binding on the combination may be why it is grabbed here,
+            // and the raw key stream is never paused
+            if self.registered_hotkeys.get(&key).is_some_and(|registration| !self.is_live(&key, registration)) {
+                continue;
+            }
+            let Some(registration) = self.registered_hotkeys.get_mut(&key) else { continue };
+            registration.active = true;
+            registration.fire_count += 1;
+            if !registration.events.contains(&HotkeyEventKind::Pressed) {
+                continue;
+            }
+
+            let (client, original_id) = key;
+            eprintln!("[system-agent] Sending hotkey_pressed event to client {} for: {}", client, original_id);
+            self.clients.send(client, &Event::HotkeyPressed { 
+                id: original_id 
+            });
+        }
+    }
+
+    /// The hotkeys owned by `client`, sorted by id.
+    pub(crate) fn list(&self, client: ClientId) -> Vec<HotkeyInfo> {
+        let mut hotkeys: Vec<HotkeyInfo> = self.registered_hotkeys
+            .iter()
+            .filter(|((owner, _), _)| *owner == client)
+            .map(|((_, id), registration)| HotkeyInfo {
+                id: id.clone(),
+                shortcut: registration.shortcut.clone(),
+                normalized: registration.gesture.normalized(),
+                registered_at: registration.registered_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as u64),
+                fire_count: registration.fire_count,
+                enabled: registration.enabled,
+            })
+            .collect();
+        hotkeys.sort_by(|a, b| a.id.cmp(&b.id));
+        hotkeys
+    }
+
+    pub(crate) fn status(&self) -> AgentStatus {
+        AgentStatus {
+            agent_version: env!("CARGO_PKG_VERSION"),
+            backend: display_backend(),
+            uptime_ms: event::uptime().as_millis() as u64,
+            clients: self.clients.count(),
+            subscribers: self.clients.subscriber_counts(),
+            hotkeys: self.registered_hotkeys.len(),
+            queued_events: self.clients.queued_events(),
+            paused: self.paused.is_some(),
+        }
+    }
+}
+
+/// Parses what a binding asks for: its gesture, and when it applies.
+fn parse_binding(binding: &RegisterCommand, layout: Option<&Layout>) -> Result<(Gesture, Option<Condition>), String> {
+    let gesture = Gesture::parse(binding, layout)?;
+    let when = binding.when.as_ref().map(Condition::new).transpose()?;
+    Ok((gesture, when))
+}
+
+/// Whether a shortcut names keys by the character they type.
+fn types_chars(shortcut: &str) -> bool {
+    hotkey::parse(shortcut).is_ok_and(|steps| steps.iter().any(Step::types_char))
+}
+
+fn steps_to_string(steps: &[HotKey]) -> String {
+    steps.iter().map(|step| step.into_string()).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const CLIENT: ClientId = 1;
+
+    /// Grabs kept in memory. Combinations in `failing` cannot be grabbed, and
+    /// grabbing twice or letting go of what is not grabbed fails as it does
+    /// with the OS.
+    #[derive(Default)]
+    struct FakeGrabber {
+        grabbed: RefCell<BTreeSet<u32>>,
+        failing: RefCell<HashSet<u32>>,
+    }
+
+    impl Grabber for FakeGrabber {
+        fn register(&self, hotkey: HotKey) -> global_hotkey::Result<()> {
+            if self.failing.borrow().contains(&hotkey.id()) {
+                return Err(global_hotkey::Error::FailedToRegister(hotkey.into_string()));
+            }
+            if !self.grabbed.borrow_mut().insert(hotkey.id()) {
+                return Err(global_hotkey::Error::AlreadyRegistered(hotkey));
+            }
+            Ok(())
+        }
+
+        fn unregister(&self, hotkey: HotKey) -> global_hotkey::Result<()> {
+            if !self.grabbed.borrow_mut().remove(&hotkey.id()) {
+                return Err(global_hotkey::Error::FailedToUnRegister(hotkey));
+            }
+            Ok(())
+        }
+    }
+
+    fn registry() -> HotkeyRegistry<FakeGrabber> {
+        HotkeyRegistry::new(FakeGrabber::default(), Arc::new(Clients::default()), None, None)
+    }
+
+    fn binding(id: &str, shortcut: &str) -> RegisterCommand {
+        serde_json::from_value(serde_json::json!({ "id": id, "shortcut": shortcut })).unwrap()
+    }
+
+    fn shared(id: &str, shortcut: &str) -> RegisterCommand {
+        serde_json::from_value(serde_json::json!({ "id": id, "shortcut": shortcut, "on_conflict": "share" })).unwrap()
+    }
+
+    fn hotkey_id(shortcut: &str) -> u32 {
+        shortcut.parse::<HotKey>().unwrap().id()
+    }
+
+    impl HotkeyRegistry<FakeGrabber> {
+        fn fail(&self, shortcut: &str) {
+            self.manager.failing.borrow_mut().insert(hotkey_id(shortcut));
+        }
+
+        fn assert_grabbed(&self, shortcuts: &[&str]) {
+            let expected: BTreeSet<u32> = shortcuts.iter().map(|shortcut| hotkey_id(shortcut)).collect();
+            assert_eq!(*self.manager.grabbed.borrow(), expected, "grabbed instead of {:?}", shortcuts);
+        }
+
+        /// The client's ids and their shortcuts, sorted by id.
+        fn shortcuts(&self) -> Vec<(String, String)> {
+            self.list(CLIENT).into_iter().map(|info| (info.id, info.shortcut)).collect()
+        }
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(id, shortcut)| (id.to_string(), shortcut.to_string())).collect()
+    }
+
+    fn failed_ids(failures: &[HotkeyFailure]) -> Vec<&str> {
+        failures.iter().map(|failure| failure.id.as_str()).collect()
+    }
+
+    #[test]
+    fn batch_rolls_back_when_a_grab_fails() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("a", "Control+Alt+A"), "hotkey_register").unwrap();
+        registry.fail("Control+Alt+D");
+
+        let failures = registry.register_batch_atomic(CLIENT, vec![
+            binding("a", "Control+Alt+B"),
+            binding("b", "Control+Alt+C"),
+            binding("c", "Control+Alt+D"),
+        ]);
+
+        assert_eq!(failed_ids(&failures), ["c"]);
+        assert_eq!(registry.shortcuts(), pairs(&[("a", "Control+Alt+A")]));
+        registry.assert_grabbed(&["Control+Alt+A"]);
+    }
+
+    #[test]
+    fn batch_with_a_duplicate_id_keeps_the_last() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("x", "Control+Alt+0"), "hotkey_register").unwrap();
+
+        let failures = registry.register_batch_atomic(CLIENT, vec![binding("x", "Control+Alt+1"), binding("x", "Control+Alt+2")]);
+        assert!(failures.is_empty());
+        assert_eq!(registry.shortcuts(), pairs(&[("x", "Control+Alt+2")]));
+        registry.assert_grabbed(&["Control+Alt+2"]);
+
+        // Rolled back through both, to what was there before the batch
+        registry.fail("Control+Alt+9");
+        let failures = registry.register_batch_atomic(CLIENT, vec![
+            binding("x", "Control+Alt+3"),
+            binding("x", "Control+Alt+4"),
+            binding("y", "Control+Alt+9"),
+        ]);
+        assert_eq!(failed_ids(&failures), ["y"]);
+        assert_eq!(registry.shortcuts(), pairs(&[("x", "Control+Alt+2")]));
+        registry.assert_grabbed(&["Control+Alt+2"]);
+    }
+
+    #[test]
+    fn batch_restores_replaced_ids_as_they_were() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("a", "Control+Alt+A"), "hotkey_register").unwrap();
+        registry.register(CLIENT, &binding("b", "Control+Alt+B"), "hotkey_register").unwrap();
+        registry.fire([(CLIENT, "a".to_string())]);
+        let before = registry.list(CLIENT);
+        registry.fail("Control+Alt+C");
+
+        // The failing grab replaces an id of its own
+        let failures = registry.register_batch_atomic(CLIENT, vec![binding("a", "Control+Alt+X"), binding("b", "Control+Alt+C")]);
+
+        assert_eq!(failed_ids(&failures), ["b"]);
+        let after = registry.list(CLIENT);
+        assert_eq!(after.len(), 2);
+        for (before, after) in before.iter().zip(&after) {
+            assert_eq!((&before.id, &before.shortcut), (&after.id, &after.shortcut));
+            assert_eq!(before.registered_at, after.registered_at);
+            assert_eq!(before.fire_count, after.fire_count);
+        }
+        assert_eq!(after[0].fire_count, 1);
+        registry.assert_grabbed(&["Control+Alt+A", "Control+Alt+B"]);
+    }
+
+    fn actions(changes: &[SyncChange]) -> Vec<(&str, SyncAction)> {
+        let mut actions: Vec<(&str, SyncAction)> = changes.iter().map(|change| (change.id.as_str(), change.action)).collect();
+        actions.sort_by_key(|(id, _)| *id);
+        actions
+    }
+
+    fn push_editing_layer(registry: &mut HotkeyRegistry<FakeGrabber>) {
+        registry
+            .define_layer(CLIENT, "edit", vec![binding("save", "Control+Alt+S"), binding("quit", "Control+Alt+X")])
+            .unwrap();
+        assert!(registry.push_layer(CLIENT, "edit").unwrap().is_empty());
+    }
+
+    #[test]
+    fn disconnecting_releases_the_hotkeys_of_active_layers() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("quit", "Control+Alt+Q"), "hotkey_register").unwrap();
+        push_editing_layer(&mut registry);
+
+        registry.unregister_all(CLIENT).unwrap();
+        registry.forget(CLIENT);
+
+        registry.assert_grabbed(&[]);
+        assert!(registry.shortcuts().is_empty());
+    }
+
+    #[test]
+    fn sync_changes_own_hotkeys_beneath_layers() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("quit", "Control+Alt+Q"), "hotkey_register").unwrap();
+        push_editing_layer(&mut registry);
+
+        let changes = registry.sync(CLIENT, vec![binding("quit", "Control+Alt+W"), binding("open", "Control+Alt+O")]);
+
+        assert_eq!(actions(&changes), [("open", SyncAction::Added), ("quit", SyncAction::Updated)]);
+        // The layer's hotkeys are untouched, its own save among them
+        assert_eq!(
+            registry.shortcuts(),
+            pairs(&[("open", "Control+Alt+O"), ("quit", "Control+Alt+X"), ("save", "Control+Alt+S")])
+        );
+        registry.assert_grabbed(&["Control+Alt+O", "Control+Alt+X", "Control+Alt+S"]);
+
+        registry.pop_layer(CLIENT, None).unwrap();
+        assert_eq!(registry.shortcuts(), pairs(&[("open", "Control+Alt+O"), ("quit", "Control+Alt+W")]));
+        registry.assert_grabbed(&["Control+Alt+O", "Control+Alt+W"]);
+    }
+
+    #[test]
+    fn register_and_unregister_beneath_layers() {
+        let mut registry = registry();
+        push_editing_layer(&mut registry);
+
+        // Waits for the layer that binds the id to be popped
+        registry.register(CLIENT, &binding("save", "Control+Alt+1"), "hotkey_register").unwrap();
+        registry.assert_grabbed(&["Control+Alt+S", "Control+Alt+X"]);
+        assert!(registry.unregister(CLIENT, "quit").is_err());
+
+        registry.register(CLIENT, &binding("open", "Control+Alt+O"), "hotkey_register").unwrap();
+        registry.unregister_all(CLIENT).unwrap();
+        assert_eq!(registry.layers(CLIENT), ["edit"]);
+        assert_eq!(registry.shortcuts(), pairs(&[("quit", "Control+Alt+X"), ("save", "Control+Alt+S")]));
+
+        registry.pop_layer(CLIENT, None).unwrap();
+        assert!(registry.shortcuts().is_empty());
+        registry.assert_grabbed(&[]);
+    }
+
+    #[test]
+    fn batch_beneath_layers_rolls_back_too() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("save", "Control+Alt+1"), "hotkey_register").unwrap();
+        push_editing_layer(&mut registry);
+        registry.fail("Control+Alt+F");
+
+        let failures = registry.register_batch_atomic(CLIENT, vec![binding("save", "Control+Alt+2"), binding("find", "Control+Alt+F")]);
+
+        assert_eq!(failed_ids(&failures), ["find"]);
+        registry.pop_layer(CLIENT, None).unwrap();
+        assert_eq!(registry.shortcuts(), pairs(&[("save", "Control+Alt+1")]));
+        registry.assert_grabbed(&["Control+Alt+1"]);
+    }
+
+    #[test]
+    fn sync_applies_only_the_difference() {
+        let mut registry = registry();
+        for (id, shortcut) in [("keep", "Control+Alt+K"), ("move", "Control+Alt+M"), ("drop", "Control+Alt+D")] {
+            registry.register(CLIENT, &binding(id, shortcut), "hotkey_register").unwrap();
+        }
+        registry.fire([(CLIENT, "keep".to_string())]);
+        registry.fail("Control+Alt+F");
+
+        let changes = registry.sync(CLIENT, vec![
+            binding("keep", "Alt+Control+K"),
+            binding("move", "Control+Alt+N"),
+            binding("new", "Control+Alt+A"),
+            binding("broken", "Control+Alt+F"),
+        ]);
+
+        assert_eq!(actions(&changes), [
+            ("broken", SyncAction::Failed),
+            ("drop", SyncAction::Removed),
+            ("keep", SyncAction::Unchanged),
+            ("move", SyncAction::Updated),
+            ("new", SyncAction::Added),
+        ]);
+        assert!(changes.iter().all(|change| change.error.is_some() == (change.action == SyncAction::Failed)));
+        assert_eq!(
+            registry.shortcuts(),
+            pairs(&[("keep", "Alt+Control+K"), ("move", "Control+Alt+N"), ("new", "Control+Alt+A")])
+        );
+        registry.assert_grabbed(&["Control+Alt+K", "Control+Alt+N", "Control+Alt+A"]);
+        // Left alone, so it keeps its count
+        assert_eq!(registry.list(CLIENT)[0].fire_count, 1);
+    }
+
+    #[test]
+    fn sync_reports_duplicate_ids() {
+        let mut registry = registry();
+
+        let changes = registry.sync(CLIENT, vec![binding("a", "Control+Alt+A"), binding("a", "Control+Alt+B")]);
+
+        // Duplicates are turned away before anything is applied
+        assert_eq!(actions(&changes), [("a", SyncAction::Failed), ("a", SyncAction::Added)]);
+        assert_eq!(registry.shortcuts(), pairs(&[("a", "Control+Alt+A")]));
+        registry.assert_grabbed(&["Control+Alt+A"]);
+    }
+
+    #[test]
+    fn sync_swaps_shortcuts() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("a", "Control+Alt+A"), "hotkey_register").unwrap();
+        registry.register(CLIENT, &binding("b", "Control+Alt+B"), "hotkey_register").unwrap();
+
+        let changes = registry.sync(CLIENT, vec![binding("a", "Control+Alt+B"), binding("b", "Control+Alt+A")]);
+
+        assert_eq!(actions(&changes), [("a", SyncAction::Updated), ("b", SyncAction::Updated)]);
+        assert_eq!(registry.shortcuts(), pairs(&[("a", "Control+Alt+B"), ("b", "Control+Alt+A")]));
+        registry.assert_grabbed(&["Control+Alt+A", "Control+Alt+B"]);
+    }
+
+    #[test]
+    fn sync_keeps_the_old_shortcut_when_the_new_one_fails() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("a", "Control+Alt+A"), "hotkey_register").unwrap();
+        registry.register(CLIENT, &binding("b", "Control+Alt+B"), "hotkey_register").unwrap();
+        registry.register(CLIENT + 1, &binding("taken", "Control+Alt+T"), "hotkey_register").unwrap();
+        registry.fail("Control+Alt+F");
+
+        let changes = registry.sync(CLIENT, vec![binding("a", "Control+Alt+F"), binding("b", "Control+Alt+T")]);
+
+        assert_eq!(actions(&changes), [("a", SyncAction::Failed), ("b", SyncAction::Failed)]);
+        assert_eq!(registry.shortcuts(), pairs(&[("a", "Control+Alt+A"), ("b", "Control+Alt+B")]));
+        registry.assert_grabbed(&["Control+Alt+A", "Control+Alt+B", "Control+Alt+T"]);
+    }
+
+    #[test]
+    fn sync_replaces_changed_triggers_and_conflict_policies() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("a", "Control+Alt+A"), "hotkey_register").unwrap();
+        registry.register(CLIENT, &binding("b", "Control+Alt+B"), "hotkey_register").unwrap();
+        let tap = serde_json::from_value(serde_json::json!({ "id": "a", "shortcut": "Control+Alt+A", "trigger": "tap" })).unwrap();
+        let shared = serde_json::from_value(serde_json::json!({ "id": "b", "shortcut": "Control+Alt+B", "on_conflict": "share" })).unwrap();
+
+        let changes = registry.sync(CLIENT, vec![tap, shared]);
+
+        assert_eq!(actions(&changes), [("a", SyncAction::Updated), ("b", SyncAction::Updated)]);
+        assert_eq!(registry.registered_hotkeys[&(CLIENT, "a".to_string())].trigger, Trigger::Tap);
+        // Sharing now, so another shared hotkey may join it
+        let other = serde_json::from_value(serde_json::json!({ "id": "c", "shortcut": "Control+Alt+B", "on_conflict": "share" })).unwrap();
+        registry.register(CLIENT, &other, "hotkey_register").unwrap();
+        registry.assert_grabbed(&["Control+Alt+A", "Control+Alt+B"]);
+    }
+
+    #[test]
+    fn pause_keeps_only_the_excepted_grab_until_its_client_resumes() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("resume", "Control+Alt+R"), "hotkey_register").unwrap();
+        registry.register(CLIENT, &binding("a", "Control+Alt+A"), "hotkey_register").unwrap();
+        registry.register(CLIENT + 1, &binding("resume", "Control+Alt+B"), "hotkey_register").unwrap();
+
+        registry.pause_all(CLIENT, Some("resume")).unwrap();
+        registry.assert_grabbed(&["Control+Alt+R"]);
+
+        // Neither can another client take the pause over or lift it
+        assert!(registry.pause_all(CLIENT + 1, Some("resume")).is_err());
+        assert!(registry.resume_all(CLIENT + 1).is_err());
+        registry.assert_grabbed(&["Control+Alt+R"]);
+
+        registry.resume_all(CLIENT).unwrap();
+        registry.assert_grabbed(&["Control+Alt+R", "Control+Alt+A", "Control+Alt+B"]);
+    }
+
+    #[test]
+    fn pause_ends_when_its_client_goes_away() {
+        let mut registry = registry();
+        registry.register(CLIENT, &binding("a", "Control+Alt+A"), "hotkey_register").unwrap();
+        registry.register(CLIENT + 1, &binding("b", "Control+Alt+B"), "hotkey_register").unwrap();
+
+        registry.pause_all(CLIENT + 1, None).unwrap();
+        registry.assert_grabbed(&[]);
+
+        registry.unregister_all(CLIENT + 1).unwrap();
+        registry.forget(CLIENT + 1);
+        registry.assert_grabbed(&["Control+Alt+A"]);
+        assert!(!registry.status().paused);
+    }
+
+    #[test]
+    fn disabling_a_shared_hotkey_keeps_the_grab_for_the_other_holder() {
+        let mut registry = registry();
+        registry.register(CLIENT, &shared("a", "Control+Alt+S"), "hotkey_register").unwrap();
+        registry.register(CLIENT + 1, &shared("b", "Control+Alt+S"), "hotkey_register").unwrap();
+
+        registry.set_enabled(CLIENT, "a", false).unwrap();
+        registry.assert_grabbed(&["Control+Alt+S"]);
+        registry.set_enabled(CLIENT + 1, "b", false).unwrap();
+        registry.assert_grabbed(&[]);
+
+        registry.set_enabled(CLIENT, "a", true).unwrap();
+        registry.assert_grabbed(&["Control+Alt+S"]);
+        registry.set_enabled(CLIENT + 1, "b", true).unwrap();
+        registry.assert_grabbed(&["Control+Alt+S"]);
+    }
+
+    fn fire_counts(registry: &HotkeyRegistry<FakeGrabber>, client: ClientId) -> Vec<u64> {
+        registry.list(client).into_iter().map(|info| info.fire_count).collect()
+    }
+
+    #[test]
+    fn reject_and_share_do_not_mix() {
+        let mut registry = registry();
+        registry.register(CLIENT + 1, &shared("shared", "Control+Alt+S"), "hotkey_register").unwrap();
+        registry.register(CLIENT + 1, &binding("rejecting", "Control+Alt+R"), "hotkey_register").unwrap();
+
+        let error = registry.register(CLIENT, &binding("a", "Control+Alt+S"), "hotkey_register").unwrap_err();
+        assert!(error.contains("'a'") && error.contains("'shared' of client 2"), "{}", error);
+        assert!(registry.register(CLIENT, &shared("b", "Control+Alt+R"), "hotkey_register").is_err());
+
+        assert!(registry.shortcuts().is_empty());
+        registry.assert_grabbed(&["Control+Alt+S", "Control+Alt+R"]);
+    }
+
+    #[test]
+    fn shared_hotkeys_fan_out_from_one_grab() {
+        let mut registry = registry();
+        registry.register(CLIENT, &shared("a", "Control+Alt+S"), "hotkey_register").unwrap();
+        registry.register(CLIENT + 1, &shared("b", "Control+Alt+S"), "hotkey_register").unwrap();
+        registry.assert_grabbed(&["Control+Alt+S"]);
+
+        registry.dispatch(GlobalHotKeyEvent { id: hotkey_id("Control+Alt+S"), state: HotKeyState::Pressed });
+        assert_eq!(fire_counts(&registry, CLIENT), [1]);
+        assert_eq!(fire_counts(&registry, CLIENT + 1), [1]);
+    }
+
+    #[test]
+    fn a_shared_grab_is_released_with_its_last_holder() {
+        let mut registry = registry();
+        registry.register(CLIENT, &shared("a", "Control+Alt+S"), "hotkey_register").unwrap();
+        registry.register(CLIENT + 1, &shared("b", "Control+Alt+S"), "hotkey_register").unwrap();
+
+        registry.unregister(CLIENT, "a").unwrap();
+        registry.assert_grabbed(&["Control+Alt+S"]);
+        registry.unregister(CLIENT + 1, "b").unwrap();
+        registry.assert_grabbed(&[]);
+    }
+
+    #[test]
+    fn multi_taps_grab_their_combination_while_live() {
+        let mut registry = registry();
+        let double = serde_json::from_value(serde_json::json!({ "id": "double", "shortcut": "Control+K", "taps": 2 })).unwrap();
+        let modifiers = serde_json::from_value(serde_json::json!({ "id": "control", "shortcut": "Control", "taps": 2 })).unwrap();
+        registry.register(CLIENT, &double, "hotkey_register").unwrap();
+        registry.register(CLIENT, &modifiers, "hotkey_register").unwrap();
+        registry.assert_grabbed(&["Control+K"]);
+
+        registry.set_enabled(CLIENT, "double", false).unwrap();
+        registry.assert_grabbed(&[]);
+        registry.set_enabled(CLIENT, "double", true).unwrap();
+        registry.assert_grabbed(&["Control+K"]);
+
+        registry.unregister(CLIENT, "double").unwrap();
+        registry.assert_grabbed(&[]);
+    }
+}
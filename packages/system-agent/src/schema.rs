@@ -0,0 +1,14 @@
+use crate::cmd::{Request, PROTOCOL_VERSION};
+use crate::event::Envelope;
+use schemars::schema_for;
+use serde_json::{json, Value};
+
+/// JSON Schema for both directions of the stdin/stdout protocol, generated
+/// from the same types the agent uses to parse commands and emit events.
+pub fn protocol_schema() -> Value {
+    json!({
+        "protocol_version": PROTOCOL_VERSION,
+        "command": schema_for!(Request),
+        "event": schema_for!(Envelope),
+    })
+}
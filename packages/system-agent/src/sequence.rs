@@ -0,0 +1,255 @@
+use std::time::{Duration, Instant};
+
+/// How long a sequence waits for its next step before it is cancelled.
+pub const SEQUENCE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// A sequence that was under way when it was given up.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cancelled<K, S> {
+    /// The steps typed before it was given up.
+    pub prefix: Vec<S>,
+    /// The sequences that were still possible.
+    pub candidates: Vec<K>,
+}
+
+/// What a press did to the sequences.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Progress<K, S> {
+    /// It is not a step of any sequence.
+    None,
+    /// It started or continued these sequences, which wait for more steps.
+    Pending { prefix: Vec<S>, candidates: Vec<K> },
+    /// It was the last step of these sequences.
+    Completed(Vec<K>),
+}
+
+/// Matches presses against multi-step hotkeys such as `Control+Alt+K S`. The
+/// first step starts a sequence, and each following press either continues
+/// it, completes it, or cancels it. A sequence also cancels when no step
+/// comes within `SEQUENCE_TIMEOUT`.
+///
+/// The matcher keeps no timer of its own: `next_deadline` says when the
+/// pending sequence runs out, and the caller, waiting on that along with its
+/// other deadlines, reports the time with `tick`.
+#[derive(Debug)]
+pub struct SequenceMatcher<K, S> {
+    sequences: Vec<(K, Vec<S>)>,
+    pending: Option<Pending<S>>,
+}
+
+#[derive(Debug)]
+struct Pending<S> {
+    prefix: Vec<S>,
+    since: Instant,
+    deadline: Instant,
+}
+
+impl<K, S> Default for SequenceMatcher<K, S> {
+    fn default() -> Self {
+        Self { sequences: Vec::new(), pending: None }
+    }
+}
+
+impl<K: Clone + PartialEq, S: Clone + PartialEq> SequenceMatcher<K, S> {
+    pub fn add(&mut self, id: K, steps: Vec<S>) {
+        self.sequences.push((id, steps));
+    }
+
+    /// Forgets a sequence. A pending prefix that nothing continues any more is
+    /// dropped without notice.
+    pub fn remove(&mut self, id: &K) {
+        self.sequences.retain(|(other, _)| other != id);
+        if self.pending.as_ref().is_some_and(|pending| self.candidates(&pending.prefix).is_empty()) {
+            self.pending = None;
+        }
+    }
+
+    /// A step was pressed. Returns the sequence it interrupted, if it cannot
+    /// continue the pending one, along with what it started or completed.
+    pub fn press(&mut self, now: Instant, step: S) -> (Option<Cancelled<K, S>>, Progress<K, S>) {
+        let cancelled = if self.next_steps().contains(&step) { None } else { self.cancel() };
+
+        let (mut prefix, since) = self.pending.take().map_or((Vec::new(), now), |pending| (pending.prefix, pending.since));
+        prefix.push(step);
+        let candidates = self.candidates(&prefix);
+        if candidates.is_empty() {
+            return (cancelled, Progress::None);
+        }
+
+        let completed: Vec<K> = self.sequences
+            .iter()
+            .filter(|(_, steps)| *steps == prefix)
+            .map(|(id, _)| id.clone())
+            .collect();
+        if !completed.is_empty() {
+            return (cancelled, Progress::Completed(completed));
+        }
+
+        self.pending = Some(Pending { prefix: prefix.clone(), since, deadline: now + SEQUENCE_TIMEOUT });
+        (cancelled, Progress::Pending { prefix, candidates })
+    }
+
+    /// Gives up the pending sequence, if there is one.
+    pub fn cancel(&mut self) -> Option<Cancelled<K, S>> {
+        let pending = self.pending.take()?;
+        let candidates = self.candidates(&pending.prefix);
+        Some(Cancelled { prefix: pending.prefix, candidates })
+    }
+
+    /// Time has passed. Returns the pending sequence if it timed out.
+    pub fn tick(&mut self, now: Instant) -> Option<Cancelled<K, S>> {
+        if self.pending.as_ref().is_some_and(|pending| pending.deadline <= now) {
+            self.cancel()
+        } else {
+            None
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.deadline)
+    }
+
+    /// When the pending sequence started.
+    pub fn pending_since(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.since)
+    }
+
+    /// The steps typed so far, if a sequence is pending.
+    pub fn prefix(&self) -> &[S] {
+        self.pending.as_ref().map_or(&[], |pending| &pending.prefix)
+    }
+
+    /// The steps that would continue the pending sequence.
+    pub fn next_steps(&self) -> Vec<S> {
+        self.next_steps_of(|_| true)
+    }
+
+    /// The steps that would continue the pending sequence towards one of the
+    /// sequences `wanted` accepts.
+    pub fn next_steps_of(&self, wanted: impl Fn(&K) -> bool) -> Vec<S> {
+        let prefix = self.prefix();
+        if prefix.is_empty() {
+            return Vec::new();
+        }
+        let mut next: Vec<S> = Vec::new();
+        for (id, steps) in &self.sequences {
+            if wanted(id) && steps.len() > prefix.len() && steps.starts_with(prefix) && !next.contains(&steps[prefix.len()]) {
+                next.push(steps[prefix.len()].clone());
+            }
+        }
+        next
+    }
+
+    fn candidates(&self, prefix: &[S]) -> Vec<K> {
+        self.sequences
+            .iter()
+            .filter(|(_, steps)| steps.starts_with(prefix))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher() -> SequenceMatcher<&'static str, &'static str> {
+        let mut matcher = SequenceMatcher::default();
+        matcher.add("save", vec!["C-K", "S"]);
+        matcher.add("spell", vec!["C-K", "C-1"]);
+        matcher.add("deep", vec!["C-J", "A", "B"]);
+        matcher
+    }
+
+    #[test]
+    fn leader_then_step_completes() {
+        let t0 = Instant::now();
+        let mut matcher = matcher();
+
+        assert_eq!(
+            matcher.press(t0, "C-K"),
+            (None, Progress::Pending { prefix: vec!["C-K"], candidates: vec!["save", "spell"] })
+        );
+        assert_eq!(matcher.next_steps(), vec!["S", "C-1"]);
+        assert_eq!(matcher.next_steps_of(|id| *id != "save"), vec!["C-1"]);
+        assert_eq!(matcher.next_deadline(), Some(t0 + SEQUENCE_TIMEOUT));
+        assert_eq!(matcher.press(t0, "C-1"), (None, Progress::Completed(vec!["spell"])));
+        assert!(matcher.next_steps().is_empty());
+        assert_eq!(matcher.next_deadline(), None);
+    }
+
+    #[test]
+    fn each_step_restarts_the_timeout() {
+        let t0 = Instant::now();
+        let mut matcher = matcher();
+
+        matcher.press(t0, "C-J");
+        let t1 = t0 + Duration::from_millis(1000);
+        assert_eq!(
+            matcher.press(t1, "A"),
+            (None, Progress::Pending { prefix: vec!["C-J", "A"], candidates: vec!["deep"] })
+        );
+        assert_eq!(matcher.pending_since(), Some(t0));
+        assert_eq!(matcher.tick(t0 + SEQUENCE_TIMEOUT), None);
+        assert_eq!(matcher.press(t1 + Duration::from_millis(1000), "B"), (None, Progress::Completed(vec!["deep"])));
+    }
+
+    #[test]
+    fn timeout_cancels() {
+        let t0 = Instant::now();
+        let mut matcher = matcher();
+
+        matcher.press(t0, "C-K");
+        assert_eq!(matcher.tick(t0 + SEQUENCE_TIMEOUT - Duration::from_millis(1)), None);
+        assert_eq!(
+            matcher.tick(t0 + SEQUENCE_TIMEOUT),
+            Some(Cancelled { prefix: vec!["C-K"], candidates: vec!["save", "spell"] })
+        );
+        assert_eq!(matcher.press(t0 + SEQUENCE_TIMEOUT, "S"), (None, Progress::None));
+    }
+
+    #[test]
+    fn wrong_step_cancels_and_may_start_another_sequence() {
+        let t0 = Instant::now();
+        let mut matcher = matcher();
+
+        matcher.press(t0, "C-K");
+        assert_eq!(
+            matcher.press(t0, "X"),
+            (Some(Cancelled { prefix: vec!["C-K"], candidates: vec!["save", "spell"] }), Progress::None)
+        );
+
+        matcher.press(t0, "C-K");
+        assert_eq!(
+            matcher.press(t0, "C-J"),
+            (
+                Some(Cancelled { prefix: vec!["C-K"], candidates: vec!["save", "spell"] }),
+                Progress::Pending { prefix: vec!["C-J"], candidates: vec!["deep"] }
+            )
+        );
+    }
+
+    #[test]
+    fn explicit_cancel() {
+        let t0 = Instant::now();
+        let mut matcher = matcher();
+
+        assert_eq!(matcher.cancel(), None);
+        matcher.press(t0, "C-J");
+        matcher.press(t0, "A");
+        assert_eq!(matcher.cancel(), Some(Cancelled { prefix: vec!["C-J", "A"], candidates: vec!["deep"] }));
+        assert_eq!(matcher.prefix(), &[] as &[&str]);
+    }
+
+    #[test]
+    fn removing_the_last_candidate_drops_the_pending_prefix() {
+        let t0 = Instant::now();
+        let mut matcher = matcher();
+
+        matcher.press(t0, "C-J");
+        matcher.remove(&"save");
+        assert_eq!(matcher.prefix(), &["C-J"]);
+        matcher.remove(&"deep");
+        assert_eq!(matcher.next_deadline(), None);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::agent::{command_listener, send_ready, ManagerRequest};
+use crate::client::Clients;
+use crate::event::ShutdownReason;
+use crossbeam_channel::Sender;
+use std::fs;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Accepts clients on a Unix domain socket until the agent shuts down. Each
+/// connection speaks the same line-delimited protocol as stdin/stdout and gets
+/// its own hotkey namespace; its hotkeys are released when it disconnects.
+pub fn serve(
+    path: &Path,
+    hotkey_sender: Sender<ManagerRequest>,
+    shutdown_sender: Sender<ShutdownReason>,
+    clients: Arc<Clients>,
+    running: Arc<AtomicBool>,
+) {
+    let listener = match bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("[system-agent] Failed to listen on {}: {}", path.display(), e);
+            let _ = shutdown_sender.send(ShutdownReason::Error);
+            return;
+        }
+    };
+    eprintln!("[system-agent] Listening for clients on {}", path.display());
+
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = spawn_client(stream, &hotkey_sender, &shutdown_sender, &clients, &running) {
+                    eprintln!("[system-agent] Failed to set up client connection: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // Non-blocking accept lets us notice shutdown; poll like the other threads do
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => {
+                eprintln!("[system-agent] Failed to accept client: {}", e);
+            }
+        }
+    }
+
+    if let Err(e) = fs::remove_file(path) {
+        eprintln!("[system-agent] Failed to remove socket {}: {}", path.display(), e);
+    }
+    eprintln!("[system-agent] Socket server exited.");
+}
+
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            // A socket file nobody answers on is left over from a crashed agent
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another agent is already serving this socket"));
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the path exists and is not a socket")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    // Clients can see every keystroke, so keep the socket private to this
+    // user. It is bound inside a directory only this user can enter, and
+    // linked into place once it is 0600, so it is never reachable with the
+    // umask's permissions.
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the socket path names no file"))?;
+    let staging = parent.join(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        // Unlike a rename, fails rather than replace a socket another agent just created
+        fs::hard_link(&staged, path)?;
+        Ok(listener)
+    });
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        eprintln!("[system-agent] Failed to remove {}: {}", staging.display(), e);
+    }
+    let listener = bound?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn spawn_client(
+    stream: UnixStream,
+    hotkey_sender: &Sender<ManagerRequest>,
+    shutdown_sender: &Sender<ShutdownReason>,
+    clients: &Arc<Clients>,
+    running: &Arc<AtomicBool>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let hangup = stream.try_clone()?;
+    let client = clients.connect(
+        Box::new(stream.try_clone()?),
+        Box::new(move || {
+            // Ends command_listener's read, which then releases the client's hotkeys
+            let _ = hangup.shutdown(Shutdown::Both);
+        }),
+    );
+    send_ready(clients, client);
+
+    let hotkey_sender = hotkey_sender.clone();
+    let shutdown_sender = shutdown_sender.clone();
+    let clients = clients.clone();
+    let running = running.clone();
+    thread::spawn(move || {
+        command_listener(client, stream, &hotkey_sender, &shutdown_sender, &clients, &running);
+        clients.disconnect(client);
+        // Ignore the error: the hotkey manager is already gone if we are shutting down
+        let _ = hotkey_sender.send(ManagerRequest::disconnect(client));
+    });
+    Ok(())
+}
@@ -0,0 +1,566 @@
+use crate::keys::{self, Side};
+use global_hotkey::hotkey::{Code, HotKey, Modifiers};
+use rdev::Key;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// What is tapped: modifiers plus a key, or modifiers on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chord {
+    pub modifiers: Modifiers,
+    /// Those of `modifiers` pressed, or required, on the left or the right
+    /// side. Only modifier-only chords tell the sides apart.
+    pub left: Modifiers,
+    pub right: Modifiers,
+    pub key: Option<Code>,
+}
+
+impl Chord {
+    /// The combination to grab for this chord, unless it is modifiers alone.
+    pub fn hotkey(&self) -> Option<HotKey> {
+        self.key.map(|key| HotKey::new(Some(self.modifiers), key))
+    }
+
+    /// Whether `typed`, as it came from the keyboard, is this chord. Sides
+    /// only need to match where this chord asks for one.
+    pub fn accepts(&self, typed: &Chord) -> bool {
+        self.key == typed.key
+            && self.modifiers == typed.modifiers
+            && typed.left.contains(self.left)
+            && typed.right.contains(self.right)
+    }
+}
+
+impl From<HotKey> for Chord {
+    fn from(hotkey: HotKey) -> Self {
+        Self { modifiers: hotkey.mods, left: Modifiers::empty(), right: Modifiers::empty(), key: Some(hotkey.key) }
+    }
+}
+
+impl fmt::Display for Chord {
+    /// Spelled like `HotKey::into_string`, with a `left`/`right` prefix on modifiers tied to a side.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(key) = self.key {
+            return write!(f, "{}", HotKey::new(Some(self.modifiers), key));
+        }
+        let mut names = Vec::new();
+        for (modifier, name) in [
+            (Modifiers::SHIFT, "shift"),
+            (Modifiers::CONTROL, "control"),
+            (Modifiers::ALT, "alt"),
+            (Modifiers::SUPER, "super"),
+        ] {
+            if !self.modifiers.contains(modifier) {
+                continue;
+            }
+            match (self.left.contains(modifier), self.right.contains(modifier)) {
+                (false, false) => names.push(name.to_string()),
+                (left, right) => {
+                    if left {
+                        names.push(format!("left{}", name));
+                    }
+                    if right {
+                        names.push(format!("right{}", name));
+                    }
+                }
+            }
+        }
+        write!(f, "{}", names.join("+"))
+    }
+}
+
+/// A chord tapped a number of times in quick succession.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapPattern {
+    pub chord: Chord,
+    /// With a single tap the pattern fires right on release, however long
+    /// the chord was held.
+    pub taps: u32,
+    /// Upper bound for each tap and for each pause between two taps.
+    pub within: Duration,
+}
+
+/// How far the key stream has got through one pattern.
+#[derive(Debug, Default)]
+struct Progress {
+    /// Taps of the chord so far in the current run.
+    count: u32,
+    last_release: Option<Instant>,
+    /// A key went down since the last tap, which may turn out to be one more.
+    continuing: bool,
+}
+
+/// A tap being typed: everything that went down since the keyboard was idle,
+/// for a modifier-only chord, or a key pressed on top of modifiers.
+#[derive(Debug)]
+struct Attempt {
+    chord: Chord,
+    /// The rdev key behind `chord.key`, whose release completes the tap.
+    key: Option<Key>,
+    started: Instant,
+}
+
+/// Recognizes tap patterns in the raw key stream. A tap is a chord pressed
+/// and released with no other key or mouse button in between. A multi-tap
+/// pattern fires once its chord has been tapped exactly `taps` times and
+/// `within` has passed without another key going down, so tapping once more
+/// than a pattern asks for cancels it.
+///
+/// Taps are timed by when the key hook saw each event, which the caller
+/// passes in, not by when the recognizer gets to it, so a busy thread does not
+/// turn quick taps into long ones.
+#[derive(Debug)]
+pub struct TapRecognizer<K> {
+    patterns: Vec<(K, TapPattern, Progress)>,
+    /// Keys currently down, in the order they went down.
+    held: Vec<Key>,
+    attempt: Option<Attempt>,
+}
+
+impl<K> Default for TapRecognizer<K> {
+    fn default() -> Self {
+        Self { patterns: Vec::new(), held: Vec::new(), attempt: None }
+    }
+}
+
+impl<K: Clone + PartialEq> TapRecognizer<K> {
+    pub fn add(&mut self, id: K, pattern: TapPattern) {
+        self.patterns.push((id, pattern, Progress::default()));
+    }
+
+    pub fn remove(&mut self, id: &K) {
+        self.patterns.retain(|(other, _, _)| other != id);
+    }
+
+    /// A key went down. Returns the patterns that fired because the pause
+    /// after their last tap ran out before this press.
+    pub fn key_down(&mut self, now: Instant, key: Key) -> Vec<K> {
+        // Auto-repeat
+        if self.held.contains(&key) {
+            return Vec::new();
+        }
+        let fired = self.tick(now);
+        let idle = self.held.is_empty();
+        let other_key_held = self.held.iter().any(|held| keys::modifier(*held).is_none());
+        self.held.push(key);
+
+        self.attempt = match (keys::modifier(key), self.attempt.take()) {
+            (Some(modifier), Some(Attempt { chord, key: None, started })) => {
+                Some(Attempt { chord: with_modifier(chord, modifier, key), key: None, started })
+            }
+            (Some(modifier), None) if idle => {
+                let chord = Chord { modifiers: Modifiers::empty(), left: Modifiers::empty(), right: Modifiers::empty(), key: None };
+                Some(Attempt { chord: with_modifier(chord, modifier, key), key: None, started: now })
+            }
+            // A modifier on top of a key, or after the modifiers alone were spoiled
+            (Some(_), _) => None,
+            (None, _) if other_key_held => None,
+            (None, _) => keys::code(key).map(|code| {
+                let chord = Chord { modifiers: self.held_modifiers(), left: Modifiers::empty(), right: Modifiers::empty(), key: Some(code) };
+                Attempt { chord, key: Some(key), started: now }
+            }),
+        };
+
+        if self.attempt.is_none() {
+            self.reset();
+        } else {
+            for (_, _, progress) in &mut self.patterns {
+                if progress.count > 0 {
+                    progress.continuing = true;
+                }
+            }
+        }
+        fired
+    }
+
+    /// A mouse button went down, which spoils the tap being typed, as in a
+    /// Control+click. Returns the patterns that fired before it.
+    pub fn button_down(&mut self, now: Instant) -> Vec<K> {
+        let fired = self.tick(now);
+        self.attempt = None;
+        self.reset();
+        fired
+    }
+
+    /// A key came up. Returns the single-tap patterns it completed, and the
+    /// patterns that fired because the pause after their last tap ran out
+    /// before this release.
+    pub fn key_up(&mut self, now: Instant, key: Key) -> Vec<K> {
+        // Held since before we started listening
+        let Some(position) = self.held.iter().position(|held| *held == key) else {
+            return Vec::new();
+        };
+        let mut fired = self.tick(now);
+        self.held.remove(position);
+
+        let completed = match &self.attempt {
+            Some(Attempt { key: Some(main), .. }) => *main == key,
+            Some(Attempt { key: None, .. }) => self.held.is_empty(),
+            None => false,
+        };
+        if completed {
+            let attempt = self.attempt.take().expect("checked above");
+            fired.extend(self.tapped(attempt.chord, attempt.started, now));
+        }
+        fired
+    }
+
+    /// Time has passed. Returns the patterns whose last tap is now far enough
+    /// in the past to be sure no further tap follows.
+    pub fn tick(&mut self, now: Instant) -> Vec<K> {
+        let mut fired = Vec::new();
+        for (id, pattern, progress) in &mut self.patterns {
+            if Self::deadline(pattern, progress).is_some_and(|deadline| deadline <= now) {
+                fired.push(id.clone());
+                *progress = Progress::default();
+            }
+        }
+        fired
+    }
+
+    /// When `tick` next has something to do, if a pattern is waiting to fire.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.patterns
+            .iter()
+            .filter_map(|(_, pattern, progress)| Self::deadline(pattern, progress))
+            .min()
+    }
+
+    fn deadline(pattern: &TapPattern, progress: &Progress) -> Option<Instant> {
+        if progress.count != pattern.taps || progress.continuing {
+            return None;
+        }
+        progress.last_release.map(|released| released + pattern.within)
+    }
+
+    /// The modifiers currently down.
+    pub fn held_modifiers(&self) -> Modifiers {
+        self.held
+            .iter()
+            .filter_map(|key| keys::modifier(*key))
+            .fold(Modifiers::empty(), |modifiers, modifier| modifiers | modifier)
+    }
+
+    fn reset(&mut self) {
+        for (_, _, progress) in &mut self.patterns {
+            *progress = Progress::default();
+        }
+    }
+
+    /// Counts a tap of `chord` towards the patterns. Returns the single-tap
+    /// patterns it completed.
+    fn tapped(&mut self, chord: Chord, started: Instant, released: Instant) -> Vec<K> {
+        let mut fired = Vec::new();
+        for (id, pattern, progress) in &mut self.patterns {
+            if !pattern.chord.accepts(&chord) {
+                *progress = Progress::default();
+                continue;
+            }
+            if pattern.taps == 1 {
+                fired.push(id.clone());
+                continue;
+            }
+            if released.saturating_duration_since(started) >= pattern.within {
+                *progress = Progress::default();
+                continue;
+            }
+            let continues = progress.count > 0
+                && progress.last_release.is_some_and(|last| started.saturating_duration_since(last) < pattern.within);
+            progress.count = if continues { progress.count + 1 } else { 1 };
+            progress.last_release = Some(released);
+            progress.continuing = false;
+        }
+        fired
+    }
+}
+
+/// `chord` with the modifier of `key` added, on the side `key` is on.
+fn with_modifier(mut chord: Chord, modifier: Modifiers, key: Key) -> Chord {
+    chord.modifiers |= modifier;
+    match keys::side(key) {
+        Some(Side::Left) => chord.left |= modifier,
+        Some(Side::Right) => chord.right |= modifier,
+        None => {}
+    }
+    chord
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hotkey;
+    use crate::trigger::DEFAULT_TAP_LIMIT;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn double(chord: Chord) -> TapPattern {
+        TapPattern { chord, taps: 2, within: DEFAULT_TAP_LIMIT }
+    }
+
+    fn control() -> Chord {
+        hotkey::parse("Control").unwrap()[0].typed(None).unwrap()
+    }
+
+    fn control_k() -> Chord {
+        Chord::from(HotKey::new(Some(Modifiers::CONTROL), Code::KeyK))
+    }
+
+    fn single(shortcut: &str) -> TapPattern {
+        TapPattern { chord: hotkey::parse(shortcut).unwrap()[0].typed(None).unwrap(), taps: 1, within: DEFAULT_TAP_LIMIT }
+    }
+
+    /// Replays `(milliseconds, key, down)` steps from `t0`, collecting what fired.
+    fn replay(recognizer: &mut TapRecognizer<&'static str>, t0: Instant, steps: &[(u64, Key, bool)]) -> Vec<&'static str> {
+        let mut fired = Vec::new();
+        for &(at, key, down) in steps {
+            let now = t0 + ms(at);
+            fired.extend(if down { recognizer.key_down(now, key) } else { recognizer.key_up(now, key) });
+        }
+        fired
+    }
+
+    /// Taps `key` once per start time, holding it for 50 ms each time.
+    fn taps(key: Key, starts: &[u64]) -> Vec<(u64, Key, bool)> {
+        starts.iter().flat_map(|&at| [(at, key, true), (at + 50, key, false)]).collect()
+    }
+
+    #[test]
+    fn double_tap_of_a_modifier_fires_after_the_window() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("open", double(control()));
+
+        assert!(replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0, 200])).is_empty());
+        assert_eq!(recognizer.next_deadline(), Some(t0 + ms(550)));
+        assert!(recognizer.tick(t0 + ms(549)).is_empty());
+        assert_eq!(recognizer.tick(t0 + ms(550)), vec!["open"]);
+        assert!(recognizer.tick(t0 + ms(2000)).is_empty());
+    }
+
+    #[test]
+    fn left_and_right_modifiers_make_the_same_chord() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("open", double(control()));
+
+        let mut steps = taps(Key::ControlLeft, &[0]);
+        steps.extend(taps(Key::ControlRight, &[200]));
+        replay(&mut recognizer, t0, &steps);
+        assert_eq!(recognizer.tick(t0 + ms(600)), vec!["open"]);
+    }
+
+    #[test]
+    fn triple_tap_cancels_double_tap() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        assert!(replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0, 200, 400])).is_empty());
+        assert_eq!(recognizer.next_deadline(), None);
+        assert!(recognizer.tick(t0 + ms(2000)).is_empty());
+    }
+
+    #[test]
+    fn triple_tap_fires_instead_of_double_tap() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+        recognizer.add("triple", TapPattern { chord: control(), taps: 3, within: DEFAULT_TAP_LIMIT });
+
+        replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0, 200]));
+        // The third press comes before the double tap's window runs out
+        assert!(replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[400])).is_empty());
+        assert_eq!(recognizer.tick(t0 + ms(750)), vec!["triple"]);
+    }
+
+    #[test]
+    fn next_press_after_the_window_fires_first() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0, 200]));
+        assert_eq!(replay(&mut recognizer, t0, &[(600, Key::ControlLeft, true)]), vec!["double"]);
+    }
+
+    #[test]
+    fn slow_taps_do_not_count() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        // Too long a pause, then too long a press
+        replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0, 400]));
+        replay(&mut recognizer, t0, &[(600, Key::ControlLeft, true), (1000, Key::ControlLeft, false)]);
+        assert!(recognizer.tick(t0 + ms(5000)).is_empty());
+    }
+
+    #[test]
+    fn pause_restarts_the_count() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0, 1000, 1200]));
+        assert_eq!(recognizer.tick(t0 + ms(1600)), vec!["double"]);
+    }
+
+    #[test]
+    fn modifier_used_in_a_combination_is_not_a_tap() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0]));
+        replay(&mut recognizer, t0, &[
+            (200, Key::ControlLeft, true),
+            (220, Key::KeyC, true),
+            (240, Key::KeyC, false),
+            (260, Key::ControlLeft, false),
+        ]);
+        assert!(recognizer.tick(t0 + ms(2000)).is_empty());
+    }
+
+    #[test]
+    fn other_key_in_between_breaks_the_run() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        let mut steps = taps(Key::ControlLeft, &[0]);
+        steps.extend(taps(Key::KeyA, &[100]));
+        steps.extend(taps(Key::ControlLeft, &[200]));
+        replay(&mut recognizer, t0, &steps);
+        assert!(recognizer.tick(t0 + ms(2000)).is_empty());
+    }
+
+    #[test]
+    fn combination_taps_while_holding_the_modifiers() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control_k()));
+
+        let mut steps = vec![(0, Key::ControlLeft, true)];
+        steps.extend(taps(Key::KeyK, &[50, 200]));
+        steps.push((300, Key::ControlLeft, false));
+        replay(&mut recognizer, t0, &steps);
+        assert_eq!(recognizer.tick(t0 + ms(600)), vec!["double"]);
+    }
+
+    #[test]
+    fn combination_taps_pressing_the_modifiers_each_time() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control_k()));
+
+        replay(&mut recognizer, t0, &[
+            (0, Key::ControlRight, true),
+            (30, Key::KeyK, true),
+            (60, Key::ControlRight, false),
+            (80, Key::KeyK, false),
+            (200, Key::ControlLeft, true),
+            (230, Key::KeyK, true),
+            (260, Key::KeyK, false),
+            (280, Key::ControlLeft, false),
+        ]);
+        assert_eq!(recognizer.tick(t0 + ms(600)), vec!["double"]);
+    }
+
+    #[test]
+    fn key_without_its_modifiers_is_another_chord() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control_k()));
+
+        replay(&mut recognizer, t0, &taps(Key::KeyK, &[0, 200]));
+        assert!(recognizer.tick(t0 + ms(2000)).is_empty());
+    }
+
+    #[test]
+    fn auto_repeat_and_unknown_releases_are_ignored() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        replay(&mut recognizer, t0, &[
+            (0, Key::ShiftLeft, false),
+            (10, Key::ControlLeft, true),
+            (40, Key::ControlLeft, true),
+            (60, Key::ControlLeft, false),
+        ]);
+        replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[200]));
+        assert_eq!(recognizer.tick(t0 + ms(600)), vec!["double"]);
+    }
+
+    #[test]
+    fn removed_pattern_does_not_fire() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("double", double(control()));
+
+        replay(&mut recognizer, t0, &taps(Key::ControlLeft, &[0, 200]));
+        recognizer.remove(&"double");
+        assert!(recognizer.tick(t0 + ms(600)).is_empty());
+    }
+
+    #[test]
+    fn single_tap_of_a_modifier_fires_on_release() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("overlay", single("RightAlt"));
+
+        assert!(replay(&mut recognizer, t0, &[(0, Key::AltGr, true)]).is_empty());
+        // However long it was held
+        assert_eq!(replay(&mut recognizer, t0, &[(2000, Key::AltGr, false)]), vec!["overlay"]);
+        assert_eq!(recognizer.next_deadline(), None);
+    }
+
+    #[test]
+    fn sides_are_told_apart_when_asked_for() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("right", single("RightAlt"));
+        recognizer.add("left", single("LeftAlt"));
+        recognizer.add("either", single("Alt"));
+
+        assert_eq!(replay(&mut recognizer, t0, &taps(Key::AltGr, &[0])), vec!["right", "either"]);
+        assert_eq!(replay(&mut recognizer, t0, &taps(Key::Alt, &[100])), vec!["left", "either"]);
+    }
+
+    #[test]
+    fn single_tap_needs_exactly_its_modifiers() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("super", single("Super"));
+        recognizer.add("shift-super", single("Shift+Super"));
+
+        let fired = replay(&mut recognizer, t0, &[
+            (0, Key::MetaLeft, true),
+            (20, Key::ShiftRight, true),
+            (40, Key::MetaLeft, false),
+            (60, Key::ShiftRight, false),
+        ]);
+        assert_eq!(fired, vec!["shift-super"]);
+    }
+
+    #[test]
+    fn other_key_or_click_spoils_a_single_tap() {
+        let t0 = Instant::now();
+        let mut recognizer = TapRecognizer::default();
+        recognizer.add("control", single("Control"));
+
+        let fired = replay(&mut recognizer, t0, &[
+            (0, Key::ControlLeft, true),
+            (20, Key::KeyC, true),
+            (40, Key::KeyC, false),
+            (60, Key::ControlLeft, false),
+        ]);
+        assert!(fired.is_empty());
+
+        recognizer.key_down(t0 + ms(100), Key::ControlRight);
+        recognizer.button_down(t0 + ms(120));
+        assert!(recognizer.key_up(t0 + ms(140), Key::ControlRight).is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::cmd::Trigger;
+use std::time::{Duration, Instant};
+
+/// Longest press that still counts as a tap when no `hold` trigger shares the
+/// combination. With one, anything shorter than the shortest hold is a tap.
+/// Also the longest tap, and longest pause between taps, of a multi-tap
+/// hotkey without `within_ms`.
+pub const DEFAULT_TAP_LIMIT: Duration = Duration::from_millis(300);
+
+/// Press/release timing of one grabbed combination. Given the triggers of the
+/// hotkeys sharing the combination, it decides which of them fire and when.
+///
+/// Time is always passed in rather than read from the clock, so the decisions
+/// can be tested without waiting.
+#[derive(Debug, Default)]
+pub struct TriggerTimer {
+    /// When the combination went down, and how long into the press hold
+    /// triggers have already been checked.
+    press: Option<(Instant, Duration)>,
+}
+
+impl TriggerTimer {
+    /// The combination went down. Returns the indices of the triggers that
+    /// fire right away.
+    pub fn press(&mut self, now: Instant, triggers: &[Trigger]) -> Vec<usize> {
+        self.press = Some((now, Duration::ZERO));
+        matching(triggers, |trigger| trigger == Trigger::Press)
+    }
+
+    /// Time has passed while the combination is held. Returns the hold
+    /// triggers whose threshold was reached since the previous call.
+    pub fn tick(&mut self, now: Instant, triggers: &[Trigger]) -> Vec<usize> {
+        let Some((pressed_at, checked)) = &mut self.press else {
+            return Vec::new();
+        };
+        let held = now.saturating_duration_since(*pressed_at);
+        let from = *checked;
+        *checked = held.max(from);
+        matching(triggers, |trigger| matches!(trigger, Trigger::Hold(threshold) if from < threshold && threshold <= held))
+    }
+
+    /// The combination came up. Returns how long it was held together with the
+    /// triggers that fire now: holds reached since the last tick, and taps if
+    /// the press was short enough. `None` if the press was never seen.
+    pub fn release(&mut self, now: Instant, triggers: &[Trigger]) -> Option<(Duration, Vec<usize>)> {
+        let mut fired = self.tick(now, triggers);
+        let (pressed_at, _) = self.press.take()?;
+        let held = now.saturating_duration_since(pressed_at);
+        if held < tap_limit(triggers) {
+            fired.extend(matching(triggers, |trigger| trigger == Trigger::Tap));
+        }
+        Some((held, fired))
+    }
+
+    /// When the next hold trigger is due, if the combination is held.
+    pub fn next_deadline(&self, triggers: &[Trigger]) -> Option<Instant> {
+        let (pressed_at, checked) = self.press?;
+        triggers
+            .iter()
+            .filter_map(|trigger| match *trigger {
+                Trigger::Hold(threshold) if threshold > checked => Some(pressed_at + threshold),
+                _ => None,
+            })
+            .min()
+    }
+}
+
+fn tap_limit(triggers: &[Trigger]) -> Duration {
+    triggers
+        .iter()
+        .filter_map(|trigger| match *trigger {
+            Trigger::Hold(threshold) => Some(threshold),
+            _ => None,
+        })
+        .min()
+        .unwrap_or(DEFAULT_TAP_LIMIT)
+}
+
+fn matching(triggers: &[Trigger], predicate: impl Fn(Trigger) -> bool) -> Vec<usize> {
+    triggers
+        .iter()
+        .enumerate()
+        .filter(|(_, trigger)| predicate(**trigger))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn press_fires_on_press_only() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Press];
+        let mut timer = TriggerTimer::default();
+
+        assert_eq!(timer.press(t0, &triggers), vec![0]);
+        assert!(timer.tick(t0 + ms(1000), &triggers).is_empty());
+        assert_eq!(timer.release(t0 + ms(1200), &triggers), Some((ms(1200), vec![])));
+    }
+
+    #[test]
+    fn tap_fires_on_a_short_release() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Tap];
+        let mut timer = TriggerTimer::default();
+
+        assert!(timer.press(t0, &triggers).is_empty());
+        assert_eq!(timer.release(t0 + ms(120), &triggers), Some((ms(120), vec![0])));
+    }
+
+    #[test]
+    fn tap_does_not_fire_on_a_long_release() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Tap];
+        let mut timer = TriggerTimer::default();
+
+        timer.press(t0, &triggers);
+        assert_eq!(timer.release(t0 + DEFAULT_TAP_LIMIT, &triggers), Some((DEFAULT_TAP_LIMIT, vec![])));
+    }
+
+    #[test]
+    fn hold_fires_once_when_its_threshold_is_reached() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Hold(ms(500))];
+        let mut timer = TriggerTimer::default();
+
+        assert!(timer.press(t0, &triggers).is_empty());
+        assert_eq!(timer.next_deadline(&triggers), Some(t0 + ms(500)));
+        assert!(timer.tick(t0 + ms(499), &triggers).is_empty());
+        assert_eq!(timer.tick(t0 + ms(500), &triggers), vec![0]);
+        assert!(timer.tick(t0 + ms(800), &triggers).is_empty());
+        assert_eq!(timer.next_deadline(&triggers), None);
+        assert_eq!(timer.release(t0 + ms(900), &triggers), Some((ms(900), vec![])));
+    }
+
+    #[test]
+    fn hold_reached_between_ticks_fires_on_release() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Hold(ms(500))];
+        let mut timer = TriggerTimer::default();
+
+        timer.press(t0, &triggers);
+        assert!(timer.tick(t0 + ms(400), &triggers).is_empty());
+        assert_eq!(timer.release(t0 + ms(600), &triggers), Some((ms(600), vec![0])));
+    }
+
+    #[test]
+    fn tap_and_hold_on_one_shortcut_are_exclusive() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Tap, Trigger::Hold(ms(800))];
+        let mut timer = TriggerTimer::default();
+
+        // Shorter than the hold, even if longer than the default tap limit
+        timer.press(t0, &triggers);
+        assert_eq!(timer.release(t0 + ms(500), &triggers), Some((ms(500), vec![0])));
+
+        timer.press(t0 + ms(1000), &triggers);
+        assert_eq!(timer.tick(t0 + ms(1800), &triggers), vec![1]);
+        assert_eq!(timer.release(t0 + ms(2000), &triggers), Some((ms(1000), vec![])));
+    }
+
+    #[test]
+    fn several_holds_fire_in_turn() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Hold(ms(1000)), Trigger::Press, Trigger::Hold(ms(300))];
+        let mut timer = TriggerTimer::default();
+
+        assert_eq!(timer.press(t0, &triggers), vec![1]);
+        assert_eq!(timer.next_deadline(&triggers), Some(t0 + ms(300)));
+        assert_eq!(timer.tick(t0 + ms(300), &triggers), vec![2]);
+        assert_eq!(timer.next_deadline(&triggers), Some(t0 + ms(1000)));
+        assert_eq!(timer.tick(t0 + ms(1000), &triggers), vec![0]);
+    }
+
+    #[test]
+    fn release_without_press_is_ignored() {
+        let t0 = Instant::now();
+        let triggers = [Trigger::Tap];
+        let mut timer = TriggerTimer::default();
+
+        assert_eq!(timer.release(t0, &triggers), None);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::cmd::WindowScope;
+use regex::Regex;
+
+/// What is known of the window with the input focus.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FocusedWindow {
+    /// The two parts of `WM_CLASS`.
+    pub instance: String,
+    pub class: String,
+    /// The name of its process, if it tells its pid and that is on this machine.
+    pub process: Option<String>,
+    pub title: String,
+}
+
+/// A regular expression searched for in text. Two are equal when written the
+/// same, so registrations can tell whether their scope changed.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Self, String> {
+        Regex::new(source).map(Self).map_err(|e| e.to_string())
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+/// A `WindowScope`, checked and ready to match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    class: Option<String>,
+    process: Option<String>,
+    title: Option<Pattern>,
+}
+
+impl Scope {
+    pub fn new(scope: &WindowScope) -> Result<Self, String> {
+        if scope.class.is_none() && scope.process.is_none() && scope.title.is_none() {
+            return Err("when needs at least one of class, process or title".to_string());
+        }
+        let title = scope
+            .title
+            .as_deref()
+            .map(Pattern::new)
+            .transpose()
+            .map_err(|e| format!("Invalid title pattern: {}", e))?;
+        Ok(Self { class: scope.class.clone(), process: scope.process.clone(), title })
+    }
+
+    /// Whether `window` is one of the scope's. Nothing matches when the focused
+    /// window is unknown, so scoped hotkeys stay off where focus cannot be followed.
+    pub fn matches(&self, window: Option<&FocusedWindow>) -> bool {
+        let Some(window) = window else { return false };
+        let same = |a: &str, b: &str| a.to_lowercase() == b.to_lowercase();
+        self.class.as_deref().is_none_or(|class| same(class, &window.instance) || same(class, &window.class))
+            && self.process.as_deref().is_none_or(|process| window.process.as_deref().is_some_and(|name| same(process, name)))
+            && self.title.as_ref().is_none_or(|title| title.is_match(&window.title))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use x11::watch;
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use super::FocusedWindow;
+    use crossbeam_channel::{never, unbounded, Receiver};
+    use std::{fs, thread};
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt as _, EventMask, Window};
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+
+    x11rb::atom_manager! {
+        Atoms: AtomsCookie {
+            _NET_ACTIVE_WINDOW,
+            _NET_WM_NAME,
+            _NET_WM_PID,
+            UTF8_STRING,
+        }
+    }
+
+    /// Follows the focused window on a thread of its own, as the window
+    /// manager announces it in `_NET_ACTIVE_WINDOW`, along with changes to its
+    /// class and title. Returns the window focused now, if there is an X
+    /// server to ask, and the changes to come.
+    pub fn watch() -> (Option<FocusedWindow>, Receiver<Option<FocusedWindow>>) {
+        if crate::agent::display_backend() == "wayland" {
+            return (None, never());
+        }
+        let mut tracker = match Tracker::connect() {
+            Ok(tracker) => tracker,
+            Err(e) => {
+                eprintln!("[system-agent] Focused window unavailable, window-scoped hotkeys stay off: {}", e);
+                return (None, never());
+            }
+        };
+        let window = tracker.active_window();
+        tracker.follow(window);
+        let mut current = tracker.describe();
+
+        let (sender, receiver) = unbounded();
+        let initial = current.clone();
+        thread::spawn(move || loop {
+            let event = match tracker.connection.wait_for_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    eprintln!("[system-agent] Stopped following the focused window: {}", e);
+                    return;
+                }
+            };
+            let Event::PropertyNotify(event) = event else { continue };
+            if event.window == tracker.root && event.atom == tracker.atoms._NET_ACTIVE_WINDOW {
+                let window = tracker.active_window();
+                tracker.follow(window);
+            } else if event.window != tracker.active || !tracker.describes(event.atom) {
+                continue;
+            }
+            let focused = tracker.describe();
+            if focused != current {
+                current = focused.clone();
+                if sender.send(focused).is_err() {
+                    return;
+                }
+            }
+        });
+        (initial, receiver)
+    }
+
+    struct Tracker {
+        connection: RustConnection,
+        root: Window,
+        atoms: Atoms,
+        /// The window whose properties are being watched, or 0 for none.
+        active: Window,
+    }
+
+    impl Tracker {
+        fn connect() -> Result<Self, String> {
+            let (connection, screen) = x11rb::connect(None).map_err(|e| format!("Failed to connect to the X server: {}", e))?;
+            let root = connection.setup().roots[screen].root;
+            let atoms = Atoms::new(&connection)
+                .map_err(|e| e.to_string())?
+                .reply()
+                .map_err(|e| e.to_string())?;
+            connection
+                .change_window_attributes(root, &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE))
+                .map_err(|e| e.to_string())?
+                .check()
+                .map_err(|e| e.to_string())?;
+            Ok(Self { connection, root, atoms, active: 0 })
+        }
+
+        fn active_window(&self) -> Window {
+            self.property(self.root, self.atoms._NET_ACTIVE_WINDOW, AtomEnum::WINDOW.into())
+                .and_then(|reply| reply.value32().and_then(|mut values| values.next()))
+                .unwrap_or(0)
+        }
+
+        /// Moves the watch for property changes onto `window`.
+        fn follow(&mut self, window: Window) {
+            if window == self.active {
+                return;
+            }
+            // The previous window may be gone already, making this fail harmlessly
+            if self.active != 0 {
+                let _ = self.connection.change_window_attributes(self.active, &ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT));
+            }
+            if window != 0 {
+                let _ = self.connection.change_window_attributes(window, &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE));
+            }
+            let _ = self.connection.flush();
+            self.active = window;
+        }
+
+        /// Whether a change of `atom` can change what `describe` says.
+        fn describes(&self, atom: Atom) -> bool {
+            atom == u32::from(AtomEnum::WM_CLASS) || atom == u32::from(AtomEnum::WM_NAME) || atom == self.atoms._NET_WM_NAME
+        }
+
+        fn describe(&self) -> Option<FocusedWindow> {
+            if self.active == 0 {
+                return None;
+            }
+            let text = |property: Atom, kind: Atom| {
+                self.property(self.active, property, kind).map(|reply| String::from_utf8_lossy(&reply.value).into_owned())
+            };
+            let wm_class = text(AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into()).unwrap_or_default();
+            let mut parts = wm_class.split('\0');
+            let instance = parts.next().unwrap_or_default().to_string();
+            let class = parts.next().unwrap_or_default().to_string();
+            let title = text(self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING)
+                .or_else(|| text(AtomEnum::WM_NAME.into(), AtomEnum::ANY.into()))
+                .unwrap_or_default();
+            let process = self
+                .property(self.active, self.atoms._NET_WM_PID, AtomEnum::CARDINAL.into())
+                .and_then(|reply| reply.value32().and_then(|mut values| values.next()))
+                .and_then(|pid| fs::read_to_string(format!("/proc/{}/comm", pid)).ok())
+                .map(|comm| comm.trim_end().to_string());
+            Some(FocusedWindow { instance, class, process, title })
+        }
+
+        /// A property of `window`, if it has one of that type.
+        fn property(&self, window: Window, property: Atom, kind: Atom) -> Option<x11rb::protocol::xproto::GetPropertyReply> {
+            let reply = self
+                .connection
+                .get_property(false, window, property, kind, 0, u32::MAX / 4)
+                .ok()?
+                .reply()
+                .ok()?;
+            (reply.type_ != x11rb::NONE).then_some(reply)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch() -> (Option<FocusedWindow>, crossbeam_channel::Receiver<Option<FocusedWindow>>) {
+    (None, crossbeam_channel::never())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(class: Option<&str>, process: Option<&str>, title: Option<&str>) -> Scope {
+        Scope::new(&WindowScope {
+            class: class.map(str::to_string),
+            process: process.map(str::to_string),
+            title: title.map(str::to_string),
+        })
+        .unwrap()
+    }
+
+    fn editor() -> FocusedWindow {
+        FocusedWindow {
+            instance: "code".to_string(),
+            class: "Code".to_string(),
+            process: Some("code".to_string()),
+            title: "main.rs - crate - Visual Studio Code".to_string(),
+        }
+    }
+
+    #[test]
+    fn every_condition_must_hold() {
+        let window = editor();
+        assert!(scope(Some("CODE"), None, None).matches(Some(&window)));
+        assert!(scope(Some("code"), Some("Code"), Some(r"\.rs ")).matches(Some(&window)));
+        assert!(!scope(Some("code"), None, Some(r"\.toml ")).matches(Some(&window)));
+        assert!(!scope(None, Some("firefox"), None).matches(Some(&window)));
+        let unnamed = FocusedWindow { process: None, ..editor() };
+        assert!(!scope(None, Some("code"), None).matches(Some(&unnamed)));
+        assert!(!scope(Some("code"), None, None).matches(None));
+    }
+
+    #[test]
+    fn nested_quantifiers_do_not_backtrack() {
+        let window = FocusedWindow { title: "a".repeat(10_000), ..editor() };
+        assert!(!scope(None, None, Some("(a*)*b")).matches(Some(&window)));
+        assert!(!scope(None, None, Some("(a|aa)+$b")).matches(Some(&window)));
+    }
+
+    #[test]
+    fn scopes_must_say_something() {
+        let empty = WindowScope { class: None, process: None, title: None };
+        assert!(Scope::new(&empty).is_err());
+        let broken = WindowScope { title: Some("(".to_string()), ..empty };
+        assert!(Scope::new(&broken).unwrap_err().starts_with("Invalid title pattern: "));
+    }
+}
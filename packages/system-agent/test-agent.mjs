@@ -35,16 +35,16 @@ function runTest() {
                     const event = JSON.parse(message);
                     
                     // Track hotkey events
-                    if (event.event === 'hotkey_pressed') {
+                    if (event.type === 'hotkey_pressed') {
                         hotkeyEventSeen = true;
                         console.log(`[Test] ✅ HOTKEY EVENT DETECTED: ${event.id}`);
                     }
                     
                     // Track raw key events
-                    if (event.event_type === 'KeyPress' || event.event_type === 'KeyRelease') {
+                    if (event.type === 'key_press' || event.type === 'key_release') {
                         rawKeyEventsSeen.push(event);
                         if (event.key === 'KeyJ') {
-                            console.log(`[Test] ✅ RAW KEY EVENT DETECTED: ${event.event_type} ${event.key}`);
+                            console.log(`[Test] ✅ RAW KEY EVENT DETECTED: ${event.type} ${event.key}`);
                         }
                     }
                 } catch (e) {
@@ -65,6 +65,7 @@ function runTest() {
 
     // --- Phase 1: Test Raw Key Streaming (before any hotkeys registered) ---
     console.log(`\n[Test] PHASE 1: Testing Raw Key Streaming...`);
+    sendCommand({ command: 'subscribe', topics: ['raw_keys'] });
     console.log(`[Test] ==> Please press and release the '${RAW_KEY_TO_TEST}' key now.`);
     console.log(`[Test] ==> You should see KeyPress and KeyRelease events for '${RAW_KEY_TO_TEST}'.`);
     console.log(`[Test] ==> This tests that rdev is working and streaming all keys.`);
@@ -0,0 +1,33 @@
+//! Golden-file test for the stdin/stdout protocol. Any change to the command
+//! or event types shows up as a diff of `tests/golden/protocol.schema.json`.
+//!
+//! Regenerate the golden file after an intentional protocol change with:
+//! `UPDATE_GOLDEN=1 cargo test --test schema`
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn protocol_schema_matches_golden_file() {
+    let output = Command::new(env!("CARGO_BIN_EXE_system-agent"))
+        .arg("schema")
+        .output()
+        .expect("failed to run system-agent schema");
+    assert!(output.status.success(), "system-agent schema exited with {}", output.status);
+
+    let actual = String::from_utf8(output.stdout).expect("schema output is not UTF-8");
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/protocol.schema.json");
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&golden, &actual).expect("failed to write golden file");
+        return;
+    }
+
+    let expected = fs::read_to_string(&golden).expect("failed to read golden file");
+    assert!(
+        actual == expected,
+        "protocol schema differs from {}; rerun with UPDATE_GOLDEN=1 if the change is intentional",
+        golden.display()
+    );
+}
@@ -0,0 +1,125 @@
+//! End-to-end tests for the ways the agent can be asked to stop. Each one must
+//! announce `shutting_down` and exit on its own within a bounded time, even
+//! though the raw key hook never returns.
+
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Generous compared to the agent's own two second shutdown budget.
+const EXIT_DEADLINE: Duration = Duration::from_secs(5);
+
+fn spawn_agent() -> Child {
+    Command::new(env!("CARGO_BIN_EXE_system-agent"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start system-agent")
+}
+
+/// Waits for the agent to exit and returns every event it wrote.
+fn wait_for_exit(mut agent: Child) -> Vec<Value> {
+    let stdout = agent.stdout.take().expect("stdout is piped");
+    let reader = thread::spawn(move || {
+        BufReader::new(stdout)
+            .lines()
+            .map(|line| serde_json::from_str(&line.expect("failed to read stdout")).expect("event is not JSON"))
+            .collect::<Vec<Value>>()
+    });
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = agent.try_wait().expect("failed to wait for system-agent") {
+            assert!(status.success(), "system-agent exited with {}", status);
+            break;
+        }
+        if started.elapsed() > EXIT_DEADLINE {
+            let _ = agent.kill();
+            panic!("system-agent did not exit within {:?}", EXIT_DEADLINE);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    reader.join().expect("stdout reader panicked")
+}
+
+fn assert_shut_down(events: &[Value], reason: &str) {
+    let last = events.last().expect("system-agent wrote nothing");
+    assert_eq!(last["type"], "shutting_down", "last event was {}", last);
+    assert_eq!(last["reason"], reason);
+}
+
+#[test]
+fn shutdown_command_stops_the_agent() {
+    let mut agent = spawn_agent();
+    let mut stdin = agent.stdin.take().expect("stdin is piped");
+    writeln!(stdin, r#"{{"command":"register","id":"quit","shortcut":"Control+Alt+Q"}}"#).unwrap();
+    writeln!(stdin, r#"{{"command":"shutdown","request_id":"bye"}}"#).unwrap();
+
+    // Keep stdin open so that only the command can be what stops the agent
+    let events = wait_for_exit(agent);
+    drop(stdin);
+
+    let result = events.iter().find(|event| event["type"] == "result").expect("no result event");
+    assert_eq!(result["request_id"], "bye");
+    assert_eq!(result["ok"], true);
+    assert_shut_down(&events, "command");
+}
+
+#[test]
+fn stdin_eof_stops_the_agent() {
+    let mut agent = spawn_agent();
+    drop(agent.stdin.take());
+
+    let events = wait_for_exit(agent);
+    assert_eq!(events[0]["type"], "ready");
+    assert_shut_down(&events, "stdin_closed");
+}
+
+/// Starts the agent from a shell that exits right away, while this test keeps
+/// the agent's stdin open, so that only parent-death detection can stop it.
+#[cfg(target_os = "linux")]
+#[test]
+fn parent_exit_stops_the_agent() {
+    use std::fs;
+
+    let output = std::env::temp_dir().join(format!("system-agent-parent-exit-{}.jsonl", std::process::id()));
+    let mut shell = Command::new("sh")
+        .arg("-c")
+        // Background jobs get /dev/null as stdin unless it is redirected explicitly.
+        // Waiting for `ready` makes sure the agent saw its parent before it goes away.
+        .arg(r#"exec 3<&0; "$0" <&3 >"$1" 2>/dev/null & echo $!; while [ ! -s "$1" ]; do sleep 0.05; done"#)
+        .arg(env!("CARGO_BIN_EXE_system-agent"))
+        .arg(&output)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start sh");
+    let stdin = shell.stdin.take().expect("stdin is piped");
+    let mut pid = String::new();
+    BufReader::new(shell.stdout.take().expect("stdout is piped")).read_line(&mut pid).unwrap();
+    let pid = pid.trim().to_string();
+    assert!(shell.wait().unwrap().success());
+
+    // The agent is reparented and may never be reaped, so treat a zombie as exited
+    let exited = || {
+        fs::read_to_string(format!("/proc/{}/stat", pid))
+            .map_or(true, |stat| stat.rsplit(')').next().is_some_and(|rest| rest.trim_start().starts_with('Z')))
+    };
+    let started = Instant::now();
+    while !exited() {
+        assert!(started.elapsed() < EXIT_DEADLINE, "system-agent did not exit within {:?}", EXIT_DEADLINE);
+        thread::sleep(Duration::from_millis(20));
+    }
+    drop(stdin);
+
+    let events: Vec<Value> = fs::read_to_string(&output)
+        .expect("failed to read agent output")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("event is not JSON"))
+        .collect();
+    let _ = fs::remove_file(&output);
+    assert_shut_down(&events, "parent_exited");
+}
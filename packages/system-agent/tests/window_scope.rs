@@ -0,0 +1,251 @@
+//! End-to-end test of window-scoped hotkeys against a real X server. Starts
+//! Xvfb with two dummy windows, plays the window manager by setting
+//! `_NET_ACTIVE_WINDOW`, and types the shortcut with XTEST. Ignored by
+//! default; run it with `cargo test --test window_scope -- --ignored` where
+//! Xvfb is installed.
+#![cfg(target_os = "linux")]
+
+use serde_json::Value;
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    AtomEnum, ConnectionExt as _, CreateWindowAux, EventMask, InputFocus, PropMode, Window, WindowClass, KEY_PRESS_EVENT,
+    KEY_RELEASE_EVENT,
+};
+use x11rb::protocol::xtest::ConnectionExt as _;
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+
+const DEADLINE: Duration = Duration::from_secs(5);
+
+/// Kills the process it holds when dropped, so a failing test leaves nothing behind.
+struct Killed(Child);
+
+impl Drop for Killed {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn xvfb_installed() -> bool {
+    env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|dir| dir.join("Xvfb").is_file()))
+}
+
+/// Starts Xvfb on a display nobody uses and connects to it.
+fn start_xvfb() -> (Killed, String, RustConnection, usize) {
+    let number = (90..200)
+        .find(|n| !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists() && !Path::new(&format!("/tmp/.X{}-lock", n)).exists())
+        .expect("no free display number");
+    let display = format!(":{}", number);
+    let server = Command::new("Xvfb")
+        .args([display.as_str(), "-screen", "0", "640x480x24", "-nolisten", "tcp"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start Xvfb");
+    let server = Killed(server);
+
+    let started = Instant::now();
+    loop {
+        if let Ok((connection, screen)) = x11rb::connect(Some(&display)) {
+            return (server, display, connection, screen);
+        }
+        assert!(started.elapsed() < DEADLINE, "Xvfb did not come up on {}", display);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// The agent, with its events read on a thread of their own.
+struct Agent {
+    _process: Killed,
+    stdin: ChildStdin,
+    events: Receiver<Value>,
+}
+
+impl Agent {
+    fn spawn(display: &str) -> Self {
+        let mut process = Command::new(env!("CARGO_BIN_EXE_system-agent"))
+            .env("DISPLAY", display)
+            .env_remove("WAYLAND_DISPLAY")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start system-agent");
+        let stdin = process.stdin.take().expect("stdin is piped");
+        let stdout = process.stdout.take().expect("stdout is piped");
+        let (sender, events) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { return };
+                if sender.send(serde_json::from_str(&line).expect("event is not JSON")).is_err() {
+                    return;
+                }
+            }
+        });
+        Self { _process: Killed(process), stdin, events }
+    }
+
+    fn send(&mut self, command: &str) {
+        writeln!(self.stdin, "{}", command).expect("failed to write to system-agent");
+    }
+
+    /// The next event of type `kind` within `timeout`, skipping others.
+    fn next(&self, kind: &str, timeout: Duration) -> Option<Value> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let event = self.events.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok()?;
+            if event["type"] == kind {
+                return Some(event);
+            }
+        }
+    }
+}
+
+/// Two dummy application windows, and the X connection playing their window
+/// manager and their user.
+struct Desktop {
+    connection: RustConnection,
+    root: Window,
+    editor: Window,
+    terminal: Window,
+}
+
+impl Desktop {
+    fn new(connection: RustConnection, screen: usize) -> Self {
+        let root = connection.setup().roots[screen].root;
+        let window = |class: &str| {
+            let window = connection.generate_id().unwrap();
+            connection
+                .create_window(
+                    0,
+                    window,
+                    root,
+                    0,
+                    0,
+                    100,
+                    100,
+                    0,
+                    WindowClass::INPUT_OUTPUT,
+                    0,
+                    &CreateWindowAux::new().event_mask(EventMask::KEY_PRESS),
+                )
+                .unwrap();
+            let wm_class = format!("{}\0{}\0", class, class.to_uppercase());
+            connection
+                .change_property8(PropMode::REPLACE, window, AtomEnum::WM_CLASS, AtomEnum::STRING, wm_class.as_bytes())
+                .unwrap();
+            connection.map_window(window).unwrap();
+            window
+        };
+        let editor = window("editor");
+        let terminal = window("terminal");
+        connection.sync().unwrap();
+        Self { connection, root, editor, terminal }
+    }
+
+    /// Focuses `window` the way a window manager would.
+    fn activate(&self, window: Window) {
+        let active = self.connection.intern_atom(false, b"_NET_ACTIVE_WINDOW").unwrap().reply().unwrap().atom;
+        self.connection.set_input_focus(InputFocus::POINTER_ROOT, window, x11rb::CURRENT_TIME).unwrap();
+        self.connection
+            .change_property32(PropMode::REPLACE, self.root, active, AtomEnum::WINDOW, &[window])
+            .unwrap();
+        self.connection.sync().unwrap();
+    }
+
+    fn keycode(&self, keysym: u32) -> u8 {
+        let setup = self.connection.setup();
+        let mapping = self
+            .connection
+            .get_keyboard_mapping(setup.min_keycode, setup.max_keycode - setup.min_keycode + 1)
+            .unwrap()
+            .reply()
+            .unwrap();
+        let per_keycode = usize::from(mapping.keysyms_per_keycode);
+        let index = mapping.keysyms.iter().position(|sym| *sym == keysym).expect("keysym not in the keymap");
+        setup.min_keycode + (index / per_keycode) as u8
+    }
+
+    /// Types Control+Alt+S.
+    fn press_shortcut(&self) {
+        let keys = [self.keycode(0xffe3), self.keycode(0xffe9), self.keycode(u32::from(b's'))];
+        for keycode in keys {
+            self.connection.xtest_fake_input(KEY_PRESS_EVENT, keycode, 0, self.root, 0, 0, 0).unwrap();
+        }
+        for keycode in keys.into_iter().rev() {
+            self.connection.xtest_fake_input(KEY_RELEASE_EVENT, keycode, 0, self.root, 0, 0, 0).unwrap();
+        }
+        self.connection.sync().unwrap();
+    }
+
+    /// Whether `window` received the S of the shortcut within `timeout`. The
+    /// modifiers before it are never grabbed.
+    fn received_key(&self, window: Window, timeout: Duration) -> bool {
+        let s = self.keycode(u32::from(b's'));
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            while let Some(event) = self.connection.poll_for_event().unwrap() {
+                if matches!(event, Event::KeyPress(press) if press.event == window && press.detail == s) {
+                    return true;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+}
+
+#[test]
+#[ignore = "needs Xvfb"]
+fn scoped_hotkeys_follow_the_focused_window() {
+    assert!(xvfb_installed(), "Xvfb is not installed");
+    let (_server, display, connection, screen) = start_xvfb();
+    let desktop = Desktop::new(connection, screen);
+    desktop.activate(desktop.editor);
+
+    let mut agent = Agent::spawn(&display);
+    agent.next("ready", DEADLINE).expect("no ready event");
+    agent.send(r#"{"command":"register","id":"save","shortcut":"Control+Alt+S","when":{"class":"Editor"},"request_id":"r"}"#);
+    let result = agent.next("result", DEADLINE).expect("no result event");
+    assert_eq!(result["ok"], true, "register failed: {}", result);
+
+    // Grabbed while the editor is focused, so the editor never sees it
+    desktop.press_shortcut();
+    let pressed = agent.next("hotkey_pressed", DEADLINE).expect("no hotkey_pressed in the editor");
+    assert_eq!(pressed["id"], "save");
+    assert!(!desktop.received_key(desktop.editor, Duration::from_millis(200)));
+
+    // Let go once the terminal is focused. The agent follows the focus on its
+    // own time, so keep typing until the terminal gets the keys.
+    desktop.activate(desktop.terminal);
+    let started = Instant::now();
+    loop {
+        desktop.press_shortcut();
+        if desktop.received_key(desktop.terminal, Duration::from_millis(200)) {
+            break;
+        }
+        assert!(started.elapsed() < DEADLINE, "the shortcut never reached the terminal");
+    }
+    assert_eq!(agent.next("hotkey_pressed", Duration::from_millis(300)), None);
+
+    // And grabbed again back in the editor
+    desktop.activate(desktop.editor);
+    let started = Instant::now();
+    let pressed = loop {
+        desktop.press_shortcut();
+        if let Some(pressed) = agent.next("hotkey_pressed", Duration::from_millis(200)) {
+            break pressed;
+        }
+        assert!(started.elapsed() < DEADLINE, "no hotkey_pressed back in the editor");
+    };
+    assert_eq!(pressed["id"], "save");
+}